@@ -0,0 +1,326 @@
+//! A self-contained HTML report, for people who'd rather not read the output of a terminal.
+//!
+//! The report is a single file without external assets: the styles and the script that sorts the table are inlined,
+//! and the treemap is an inline SVG.
+
+use crate::format_bytes;
+
+use duped::{DeduperResult, DirectoryWaste, DuplicateOrder};
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; }
+th, td { padding: 0.2em 0.8em; text-align: left; border-bottom: 1px solid #ddd; }
+th[data-sort] { cursor: pointer; text-decoration: underline dotted; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+code { font-size: 0.9em; }
+details { margin: 0.4em 0; }
+svg text { font-size: 11px; pointer-events: none; }
+svg rect { stroke: #fff; }
+";
+
+/// Sorts the rows of the table by the column whose header was clicked, using the `data-value` of its cells.
+const SCRIPT: &str = "\
+document.querySelectorAll('th[data-sort]').forEach((th, column) => {
+  th.addEventListener('click', () => {
+    const body = th.closest('table').tBodies[0];
+    const numeric = th.dataset.sort === 'num';
+    const descending = th.dataset.descending !== 'true';
+    th.dataset.descending = descending;
+    const value = row => row.cells[column].dataset.value;
+    const rows = Array.from(body.rows).sort((a, b) => {
+      const order = numeric ? value(a) - value(b) : value(a).localeCompare(value(b));
+      return descending ? -order : order;
+    });
+    rows.forEach(row => body.appendChild(row));
+  });
+});
+";
+
+const TREEMAP_WIDTH: f64 = 960.0;
+const TREEMAP_HEIGHT: f64 = 480.0;
+/// How many levels of directories the treemap shows.
+const TREEMAP_DEPTH: usize = 4;
+const TREEMAP_COLORS: [&str; TREEMAP_DEPTH] = ["#4e79a7", "#76b7b2", "#59a14f", "#edc948"];
+
+/// Render `duplicates` as an HTML page, listing the groups of duplicates in `order`.
+pub(crate) fn render(duplicates: &DeduperResult, order: DuplicateOrder) -> String {
+    let groups = duplicates.sorted_duplicates(order);
+    let wasted: u64 = groups.iter().map(|(_, entries)| entries.wasted_bytes()).sum();
+    let files: usize = groups.iter().map(|(_, entries)| entries.len()).sum();
+    let stats = duplicates.stats();
+
+    let mut out = String::new();
+    out += "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n";
+    out += "<title>Duplicate files</title>\n";
+    let _ = writeln!(out, "<style>\n{STYLE}</style>\n</head>\n<body>");
+
+    out += "<h1>Duplicate files</h1>\n<ul>\n";
+    let _ = writeln!(out, "<li>Groups of duplicates: {}</li>", groups.len());
+    let _ = writeln!(out, "<li>Files in these groups: {files}</li>");
+    let _ = writeln!(
+        out,
+        "<li>Space freed by keeping one copy of each file: {}</li>",
+        format_bytes(wasted)
+    );
+    // the first round of hashing overlaps with the walk, so the two durations can't be added up
+    let _ =
+        writeln!(out, "<li>Walked the roots in {:.1}s</li>", stats.walk_duration().as_secs_f64());
+    let _ = writeln!(
+        out,
+        "<li>Read {} in {:.1}s of hashing</li>",
+        format_bytes(stats.bytes_read()),
+        stats.hash_duration().as_secs_f64()
+    );
+    if !duplicates.skipped().is_empty() {
+        let _ = writeln!(out, "<li>Skipped files: {}</li>", duplicates.skipped().len());
+    }
+    if duplicates.is_partial() {
+        out += "<li>The search was stopped early, so the results are partial</li>\n";
+    }
+    out += "</ul>\n";
+
+    out += "<h2>Wasted space by directory</h2>\n";
+    treemap(&mut out, duplicates.directory_report().directories());
+
+    out += "<h2>Groups</h2>\n<table>\n<thead><tr>";
+    out += "<th data-sort=\"num\">#</th><th data-sort=\"text\">First file</th>";
+    out += "<th data-sort=\"num\">Size</th><th data-sort=\"num\">Copies</th><th data-sort=\"num\">Wasted</th>";
+    out += "</tr></thead>\n<tbody>\n";
+    for (i, (_, entries)) in groups.iter().enumerate() {
+        let first = entries.iter().next().unwrap_or_default();
+        let first = escape(&first.display().to_string());
+        let _ = writeln!(
+            out,
+            "<tr><td class=\"num\" data-value=\"{i}\"><a href=\"#group-{i}\">{}</a></td>\
+             <td data-value=\"{first}\"><code>{first}</code></td>\
+             <td class=\"num\" data-value=\"{size}\">{}</td>\
+             <td class=\"num\" data-value=\"{copies}\">{copies}</td>\
+             <td class=\"num\" data-value=\"{wasted}\">{}</td></tr>",
+            i + 1,
+            format_bytes(entries.file_size()),
+            format_bytes(entries.wasted_bytes()),
+            size = entries.file_size(),
+            copies = entries.len(),
+            wasted = entries.wasted_bytes(),
+        );
+    }
+    out += "</tbody>\n</table>\n";
+
+    out += "<h2>Files</h2>\n";
+    for (i, (hash, entries)) in groups.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "<details id=\"group-{i}\"><summary>Group {} &ndash; {} copies of {} (<code>{hash}</code>)</summary>",
+            i + 1,
+            entries.len(),
+            format_bytes(entries.file_size())
+        );
+        out += "<table>\n<thead><tr><th>File</th><th>Size</th><th>Modified (UTC)</th></tr></thead>\n<tbody>\n";
+        for entry in entries.entries() {
+            let modified = entry.modified().map(format_time).unwrap_or_default();
+            let sparse = if entry.is_sparse() { " (sparse)" } else { "" };
+            let _ = writeln!(
+                out,
+                "<tr><td><code>{}</code>{sparse}</td><td class=\"num\">{}</td><td>{modified}</td></tr>",
+                escape(&entry.path().display().to_string()),
+                format_bytes(entry.size()),
+            );
+        }
+        out += "</tbody>\n</table>\n</details>\n";
+    }
+
+    let _ = writeln!(out, "<script>\n{SCRIPT}</script>\n</body>\n</html>");
+    out
+}
+
+/// Draw the directories that hold wasted space as nested rectangles, whose areas are proportional to the space.
+fn treemap(out: &mut String, directories: &[DirectoryWaste]) {
+    if directories.is_empty() {
+        *out += "<p>No wasted space.</p>\n";
+        return;
+    }
+
+    let known: HashMap<&Path, &DirectoryWaste> =
+        directories.iter().map(|d| (d.path(), d)).collect();
+    let mut children: HashMap<&Path, Vec<&DirectoryWaste>> = HashMap::new();
+    let mut roots = vec![];
+    for dir in directories {
+        match dir.path().parent().filter(|parent| known.contains_key(parent)) {
+            Some(parent) => children.entry(parent).or_default().push(dir),
+            None => roots.push(dir),
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "<svg viewBox=\"0 0 {TREEMAP_WIDTH} {TREEMAP_HEIGHT}\" width=\"{TREEMAP_WIDTH}\" \
+         height=\"{TREEMAP_HEIGHT}\" xmlns=\"http://www.w3.org/2000/svg\">"
+    );
+    let area = Rect { x: 0.0, y: 0.0, width: TREEMAP_WIDTH, height: TREEMAP_HEIGHT };
+    let total = roots.iter().map(|d| d.wasted_bytes()).sum();
+    layout(out, &roots, total, &children, area, 0);
+    *out += "</svg>\n";
+}
+
+#[derive(Clone, Copy)]
+struct Rect {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+/// Split `area` between `dirs` along its longest side (slice-and-dice), then do the same for their subdirectories.
+///
+/// `area` stands for `total` bytes, which is more than the space wasted by `dirs` when their parent directory also
+/// directly holds redundant files: that part of the area is left empty.
+fn layout(
+    out: &mut String,
+    dirs: &[&DirectoryWaste],
+    total: u64,
+    children: &HashMap<&Path, Vec<&DirectoryWaste>>,
+    area: Rect,
+    depth: usize,
+) {
+    if depth >= TREEMAP_DEPTH || total == 0 {
+        return;
+    }
+
+    let mut offset = 0.0;
+    for dir in dirs {
+        let share = dir.wasted_bytes() as f64 / total as f64;
+        let rect = if area.width >= area.height {
+            let width = area.width * share;
+            Rect { x: area.x + offset, width, ..area }
+        } else {
+            let height = area.height * share;
+            Rect { y: area.y + offset, height, ..area }
+        };
+        offset += if area.width >= area.height { rect.width } else { rect.height };
+        if rect.width < 2.0 || rect.height < 2.0 {
+            continue;
+        }
+
+        let path = escape(&dir.path().display().to_string());
+        let _ = writeln!(
+            out,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\">\
+             <title>{path}: {}</title></rect>",
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+            TREEMAP_COLORS[depth],
+            format_bytes(dir.wasted_bytes())
+        );
+        // leave room for the name of the directory above its subdirectories
+        let header = 14.0;
+        if rect.width > 40.0 && rect.height > header {
+            let name = dir.path().file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>",
+                rect.x + 3.0,
+                rect.y + 11.0,
+                escape(if name.is_empty() { &path } else { &name })
+            );
+        }
+        if let Some(subdirs) = children.get(dir.path()) {
+            let inner = Rect {
+                x: rect.x + 2.0,
+                y: rect.y + header,
+                width: rect.width - 4.0,
+                height: rect.height - header - 2.0,
+            };
+            if inner.width > 0.0 && inner.height > 0.0 {
+                layout(out, subdirs, dir.wasted_bytes(), children, inner, depth + 1);
+            }
+        }
+    }
+}
+
+/// Escape `text` so that it can be used in HTML text and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Format `time` as a UTC date and time, e.g. `2024-02-29 13:05:09`.
+fn format_time(time: SystemTime) -> String {
+    let Ok(since_epoch) = time.duration_since(UNIX_EPOCH) else {
+        return String::new();
+    };
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // convert days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn times_are_formatted() {
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_211_909);
+        assert_eq!(format_time(leap_day), "2024-02-29 13:05:09");
+    }
+
+    #[test]
+    fn report_is_self_contained() {
+        let dir = tempfile::tempdir().unwrap();
+        for (dir_name, name) in [("a", "<x>"), ("b", "y"), ("b", "z")] {
+            std::fs::create_dir_all(dir.path().join(dir_name)).unwrap();
+            std::fs::write(dir.path().join(dir_name).join(name), b"same content").unwrap();
+        }
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let result = deduper.find(duped::ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        let html = render(&result, DuplicateOrder::WastedBytes);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(
+            html.contains("Groups of duplicates: 1") && html.contains("Files in these groups: 3")
+        );
+        assert!(html.contains("&lt;x&gt;") && !html.contains("<x>"));
+        assert!(html.contains("<svg") && html.contains("<rect"));
+        assert!(!html.contains("src=") && !html.contains("href=\"http"));
+    }
+}
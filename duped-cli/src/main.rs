@@ -1,24 +1,91 @@
-use duped::{ContentLimit, Deduper, DeduperResult};
+use duped::{
+    CacheMode, ContentLimit, Deduper, DeduperResult, DirectoryReport, DuplicateOrder, GlobFilter,
+    HashBackend, Manifest, ReadOrder, RegexFilter, RootFilter, ScanStats, SimilarDirectories,
+    SkipReason, SkippedEntry, Throttle,
+};
 
+mod html;
+
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 const HELP: &str = "\
 duped 0.1.0 -- Find duplicate files based on their hash.
 
 USAGE:
   fdup [FLAGS] [OPTIONS] PATH...
+  fdup --diff-manifests OLD NEW
 FLAGS:
   -h, --help                   Prints help information.
   -r, --remove                 Interactively remove duplicate files.
   --remove-with-same-filename  Remove duplicate files that have the same filename.
   --remove-paranoid            Remove duplicate files, but also check if they have the same content.
+  --respect-ignore-files       Skip files listed in .gitignore, .ignore, and .dupedignore files.
+  --skip-hidden                Skip files and directories whose name starts with a '.'.
+  --one-file-system            Don't descend into directories that are on other file systems.
+  --list-skipped               List the files that were not processed (e.g. symbolic links, sockets), and why.
+  --no-cache-pollution         Drop the content of hashed files from the page cache once it is hashed (Linux only).
+  --direct-io                  Bypass the page cache when reading files, using O_DIRECT (Linux only).
+  --idle-io                    Only read from disk when no other process needs to (Linux only).
+  --across-roots               Only consider the duplicates that were found in at least two different <PATH>s, when
+                               reporting and removing duplicates.
+  --within-root                Only consider the duplicates that were all found in the same <PATH>.
+  --flat                       List every group of duplicate files, rather than listing identical directories once.
+  --by-directory               Print the directories that hold the most redundant data, and the pairs of directories
+                               that share the most content (as many as --top, or 10).
+  --stats                      Print where the search spent its time, and how much it had to read.
+  --diff-manifests             Rather than searching <PATH>s, print the files that were added, removed, modified, and
+                               moved or renamed between two manifests saved with --save-manifest.
 OPTIONS:
-  -l, --lower-limit LIMIT  Files whose size is under <LIMIT> are ignored [default: 1 MiB].
+  -l, --lower-limit LIMIT  Files whose size is under <LIMIT> are ignored [default: 1 MiB, or 0 B with --backup or
+                           --save-manifest].
+  --max-depth DEPTH        Only process the files that are at most <DEPTH> levels below each <PATH>: 1 only processes
+                           the files directly inside <PATH>, 2 also the files of its subdirectories, and so on.
+  --walk-threads THREADS   Read directories using <THREADS> threads [default: number of CPUs].
+  --read-order ORDER       Read files in the order in which they are found ('discovery'), or sorted by inode
+                           ('inode') or by position on disk ('physical'), which helps rotational disks
+                           [default: discovery].
+  --max-bytes-per-sec RATE Read at most <RATE> bytes per second while hashing (e.g. '50 MiB').
+  --max-files-per-sec RATE Open at most <RATE> files per second while hashing.
+  --io-uring DEPTH         Read files with io_uring, keeping up to <DEPTH> reads in flight per thread (Linux only,
+                           requires the io-uring feature).
+  --spill-dir DIR          Keep the files that are still candidates for duplication in <DIR>, rather than in memory,
+                           once there are too many of them (see --max-candidates-in-memory).
+  --max-candidates-in-memory COUNT
+                           With --spill-dir, keep at most <COUNT> candidates in memory [default: 1000000].
+  --sort ORDER             List the groups of duplicates that waste the most space first ('wasted'), the largest
+                           files first ('size'), the most copies first ('count'), or by path ('path')
+                           [default: wasted].
+  --top N                  Only list the first <N> groups of duplicates.
+  --similar PERCENT        Print the pairs of directories whose files are at least <PERCENT>% the same (by content,
+                           regardless of names), as many as --top, or 10.
+  --format FORMAT          Print the duplicates as text ('text'), or as a self-contained HTML page ('html'), which
+                           can be redirected to a file [default: text].
+  --backup DIR             Rather than listing duplicates, list the files of the <PATH>s that have no copy (by
+                           content, regardless of name) under <DIR> (can be specified multiple times). Exits with
+                           status 1 if any file is missing, or couldn't be read.
+  --save-manifest FILE     Hash every file entirely, and save the hash of every file to <FILE>, to be compared with
+                           a later search of the same <PATH>s using --diff-manifests.
+  --stats-json FILE        Write the same statistics as --stats to <FILE>, as a single line of JSON, so that scripts
+                           don't have to parse the report.
+  --include GLOB           Only process files that match <GLOB> (can be specified multiple times).
+  --exclude GLOB           Ignore files that match <GLOB> (can be specified multiple times).
+  --include-regex REGEX    Only process files that match <REGEX> (can be specified multiple times).
+  --exclude-regex REGEX    Ignore files that match <REGEX> (can be specified multiple times).
 ARGS:
   <PATH...>                Where to start the search from (can be specified multiple times).
+
+While a search is running, sending SIGUSR1 halves the limits given by --max-bytes-per-sec and --max-files-per-sec,
+and sending SIGUSR2 doubles them.
+
+Globs and regular expressions are matched against the path of a file, relative to the <PATH> it was found in. Globs
+follow .gitignore conventions: '*.tmp' matches at any depth, 'node_modules/' matches everything inside a
+'node_modules' directory, and '/build' only matches at the top of <PATH>.
 ";
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -47,14 +114,65 @@ impl RemovalKind {
     }
 }
 
+/// How to print the duplicates.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum ReportFormat {
+    Text,
+    Html,
+}
+
+/// How to print the statistics of a search.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+/// What to do, according to the command line.
+enum Command {
+    /// Search the roots.
+    Find(Box<Args>),
+    /// Compare two manifests, the older one first.
+    DiffManifests(PathBuf, PathBuf),
+}
+
+/// All the filters that can be configured from the command line.
+type Filter = (ContentLimit, GlobFilter, RegexFilter);
+
 #[derive(Debug)]
 struct Args {
     remove: Option<RemovalKind>,
+    list_skipped: bool,
+    /// Whether to print the statistics of the search after the report.
+    stats: bool,
+    /// Where to write the statistics of the search as JSON, if anywhere.
+    stats_json: Option<PathBuf>,
+    by_directory: bool,
+    /// Whether to list the files of identical directories, rather than the directories.
+    flat: bool,
+    root_filter: RootFilter,
+    /// The minimum similarity of the directories to print, between 0 and 1.
+    similar: Option<f64>,
+    sort: DuplicateOrder,
+    format: ReportFormat,
+    /// How many groups of duplicates to list, if not all of them.
+    top: Option<usize>,
+    /// The backup directories to list the files that have no copy in, rather than the duplicates.
+    backup: Vec<PathBuf>,
+    lower_limit: u64,
+    /// Where to save the manifest of the search, if anywhere.
+    save_manifest: Option<PathBuf>,
     deduper: Deduper,
-    content_limit: ContentLimit,
+    filter: Filter,
+    /// The limits of the search, if any.
+    throttle: Option<Throttle>,
+}
+
+fn invalid_pattern(e: impl std::fmt::Display) -> pico_args::Error {
+    pico_args::Error::ArgumentParsingFailed { cause: e.to_string() }
 }
 
-fn parse_args() -> Result<Option<Args>, pico_args::Error> {
+fn parse_args() -> Result<Option<Command>, pico_args::Error> {
     let mut pargs = pico_args::Arguments::from_env();
 
     if pargs.contains(["-h", "--help"]) {
@@ -62,10 +180,68 @@ fn parse_args() -> Result<Option<Args>, pico_args::Error> {
         return Ok(None);
     }
 
+    if pargs.contains("--diff-manifests") {
+        let old = pargs.free_from_str()?;
+        let new = pargs.free_from_str()?;
+        if !pargs.finish().is_empty() {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "'--diff-manifests' only takes two manifests".into(),
+            });
+        }
+        return Ok(Some(Command::DiffManifests(old, new)));
+    }
+
+    let respect_ignore_files = pargs.contains("--respect-ignore-files");
+    let skip_hidden = pargs.contains("--skip-hidden");
+    let list_skipped = pargs.contains("--list-skipped");
+    let by_directory = pargs.contains("--by-directory");
+    let flat = pargs.contains("--flat");
+    let root_filter = match (pargs.contains("--across-roots"), pargs.contains("--within-root")) {
+        (true, true) => {
+            return Err(pico_args::Error::ArgumentParsingFailed {
+                cause: "'--across-roots' conflicts with '--within-root'".into(),
+            })
+        }
+        (true, false) => RootFilter::AcrossRoots,
+        (false, true) => RootFilter::WithinRoot,
+        (false, false) => RootFilter::All,
+    };
+    let similar = pargs.opt_value_from_fn("--similar", parse_percentage)?;
+    let stats = pargs.contains("--stats");
+    let stats_json: Option<PathBuf> = pargs.opt_value_from_str("--stats-json")?;
+    let same_file_system = pargs.contains("--one-file-system");
+    let no_cache_pollution = pargs.contains("--no-cache-pollution");
+    let direct_io = pargs.contains("--direct-io");
+    let idle_io = pargs.contains("--idle-io");
+    let max_bytes_per_sec = pargs.opt_value_from_fn("--max-bytes-per-sec", parse_byte_rate)?;
+    let max_files_per_sec = pargs.opt_value_from_fn("--max-files-per-sec", parse_file_rate)?;
+    let max_depth: Option<usize> = pargs.opt_value_from_str("--max-depth")?;
+    let walk_threads: Option<usize> = pargs.opt_value_from_str("--walk-threads")?;
+    let io_uring_depth: Option<u32> = pargs.opt_value_from_str("--io-uring")?;
+    let read_order = pargs.opt_value_from_fn("--read-order", parse_read_order)?;
+    let sort = pargs.opt_value_from_fn("--sort", parse_duplicate_order)?.unwrap_or_default();
+    let top: Option<usize> = pargs.opt_value_from_str("--top")?;
+    let format =
+        pargs.opt_value_from_fn("--format", parse_report_format)?.unwrap_or(ReportFormat::Text);
+    let spill_dir: Option<PathBuf> = pargs.opt_value_from_str("--spill-dir")?;
+    let backup: Vec<PathBuf> = pargs.values_from_str("--backup")?;
+    let save_manifest: Option<PathBuf> = pargs.opt_value_from_str("--save-manifest")?;
+    let max_candidates_in_memory: usize =
+        pargs.opt_value_from_str("--max-candidates-in-memory")?.unwrap_or(1_000_000);
     let lower_limit = pargs
         .opt_value_from_fn(["-l", "--lower-limit"], |s| byte_unit::Byte::parse_str(s, false))?
         .map(|b| b.as_u64())
-        .unwrap_or_else(|| 1024);
+        .unwrap_or(if backup.is_empty() && save_manifest.is_none() { 1024 } else { 0 });
+    let globs = GlobFilter::new(
+        pargs.values_from_str::<_, String>("--include")?,
+        pargs.values_from_str::<_, String>("--exclude")?,
+    )
+    .map_err(invalid_pattern)?;
+    let regexes = RegexFilter::new(
+        pargs.values_from_str::<_, String>("--include-regex")?,
+        pargs.values_from_str::<_, String>("--exclude-regex")?,
+    )
+    .map_err(invalid_pattern)?;
 
     let remaining = pargs.finish();
     let mut remove = None;
@@ -110,31 +286,439 @@ fn parse_args() -> Result<Option<Args>, pico_args::Error> {
         Err(pico_args::Error::ArgumentParsingFailed {
             cause: "'<PATH>' argument is missing".into(),
         })
+    } else if let (Some(kind), false) = (remove, backup.is_empty()) {
+        Err(pico_args::Error::ArgumentParsingFailed {
+            cause: format!("'{}' conflicts with '--backup'", kind.as_option()),
+        })
     } else {
-        let deduper = Deduper::builder(roots).build();
+        let mut builder = Deduper::builder(roots)
+            .respect_ignore_files(respect_ignore_files)
+            .skip_hidden(skip_hidden)
+            .same_file_system(same_file_system);
+        if let Some(depth) = max_depth {
+            builder = builder.max_depth(depth);
+        }
+        if let Some(threads) = walk_threads {
+            builder = builder.walk_threads(threads);
+        }
+        let throttle = (max_bytes_per_sec.is_some() || max_files_per_sec.is_some())
+            .then(|| Throttle::new(max_bytes_per_sec, max_files_per_sec));
+        if let Some(throttle) = &throttle {
+            builder = builder.throttle(throttle.clone());
+        }
+        if idle_io {
+            builder = builder.idle_io_priority(true);
+        }
+        if let Some(order) = read_order {
+            builder = builder.read_order(order);
+        }
+        if direct_io {
+            builder = builder.cache_mode(CacheMode::Direct);
+        } else if no_cache_pollution {
+            builder = builder.cache_mode(CacheMode::DropBehind);
+        }
+        if let Some(dir) = spill_dir {
+            builder = builder.spill_candidates(dir, max_candidates_in_memory);
+        }
+        if let Some(queue_depth) = io_uring_depth {
+            builder = builder.hash_backend(io_uring_backend(queue_depth)?);
+        }
+        if save_manifest.is_some() {
+            builder = builder.hash_all_files(true);
+        }
+        if !backup.is_empty() {
+            builder = builder.reference_roots(backup.clone());
+        }
+        let deduper = builder.build();
         let content_limit = ContentLimit::no_limit().with_lower_limit(lower_limit);
-        Ok(Some(Args { deduper, remove, content_limit }))
+        let filter = (content_limit, globs, regexes);
+        Ok(Some(Command::Find(Box::new(Args {
+            deduper,
+            remove,
+            list_skipped,
+            stats,
+            stats_json,
+            by_directory,
+            flat,
+            root_filter,
+            similar,
+            sort,
+            format,
+            top,
+            backup,
+            lower_limit,
+            save_manifest,
+            filter,
+            throttle,
+        }))))
+    }
+}
+
+fn parse_read_order(order: &str) -> Result<ReadOrder, String> {
+    match order {
+        "discovery" => Ok(ReadOrder::Discovery),
+        "inode" => Ok(ReadOrder::Inode),
+        "physical" => Ok(ReadOrder::Physical),
+        _ => Err(format!(
+            "unknown read order '{order}', expected 'discovery', 'inode', or 'physical'"
+        )),
+    }
+}
+
+fn parse_percentage(percentage: &str) -> Result<f64, String> {
+    match percentage.trim_end_matches('%').parse::<f64>() {
+        Ok(percentage) if (0.0..=100.0).contains(&percentage) => Ok(percentage / 100.0),
+        _ => Err(format!("invalid percentage '{percentage}', expected a number between 0 and 100")),
+    }
+}
+
+fn parse_byte_rate(rate: &str) -> Result<u64, String> {
+    match byte_unit::Byte::parse_str(rate, false) {
+        Ok(bytes) if bytes.as_u64() > 0 => Ok(bytes.as_u64()),
+        Ok(_) => Err(format!("invalid rate '{rate}', expected more than 0 bytes")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_file_rate(rate: &str) -> Result<u64, String> {
+    match rate.parse::<u64>() {
+        Ok(files) if files > 0 => Ok(files),
+        _ => Err(format!("invalid rate '{rate}', expected a number of files greater than 0")),
+    }
+}
+
+fn parse_report_format(format: &str) -> Result<ReportFormat, String> {
+    match format {
+        "text" => Ok(ReportFormat::Text),
+        "html" => Ok(ReportFormat::Html),
+        _ => Err(format!("unknown format '{format}', expected 'text' or 'html'")),
     }
 }
 
+fn parse_duplicate_order(order: &str) -> Result<DuplicateOrder, String> {
+    match order {
+        "wasted" => Ok(DuplicateOrder::WastedBytes),
+        "size" => Ok(DuplicateOrder::Size),
+        "path" => Ok(DuplicateOrder::FirstPath),
+        "count" => Ok(DuplicateOrder::Count),
+        _ => Err(format!("unknown order '{order}', expected 'wasted', 'size', 'path', or 'count'")),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+fn io_uring_backend(queue_depth: u32) -> Result<HashBackend, pico_args::Error> {
+    Ok(HashBackend::IoUring { queue_depth })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+fn io_uring_backend(_: u32) -> Result<HashBackend, pico_args::Error> {
+    Err(pico_args::Error::ArgumentParsingFailed {
+        cause: "'--io-uring' requires duped to be built with the 'io-uring' feature, on Linux"
+            .into(),
+    })
+}
+
 fn format_bytes(bytes: u64) -> String {
     let unit = byte_unit::Byte::from_u64(bytes).get_appropriate_unit(byte_unit::UnitType::Binary);
 
     format!("{unit:.2}")
 }
 
-fn print_stats(duplicates: DeduperResult) {
-    let mut dup_bytes = 0;
+fn print_stats(duplicates: DeduperResult, order: DuplicateOrder, top: Option<usize>, flat: bool) {
+    let mut groups = duplicates.sorted_duplicates(order);
+    let dup_bytes: u64 =
+        groups.iter().map(|(_, paths)| paths.file_size() * paths.len() as u64).sum();
+    if !flat {
+        let mut trees = duplicates.duplicate_trees();
+        if !trees.is_empty() {
+            println!("The following duplicate directories have been found:");
+        }
+        let tree_count = trees.len();
+        trees.truncate(top.unwrap_or(usize::MAX));
+        for tree in &trees {
+            println!("Tree: {}", tree.hash());
+            for path in tree.paths() {
+                println!(
+                    "-> size: {}, files: {}, directory: '{}'",
+                    format_bytes(tree.size()),
+                    tree.files(),
+                    path.display()
+                );
+            }
+        }
+        if trees.len() < tree_count {
+            println!("Listed {} of {tree_count} duplicate directories.", trees.len());
+        }
+        // the files of the duplicate trees that were listed were reported along with their tree
+        let tree_paths: HashSet<&Path> =
+            trees.iter().flat_map(|tree| tree.paths().iter().map(PathBuf::as_path)).collect();
+        groups.retain(|(_, paths)| {
+            !paths.iter().all(|path| path.ancestors().any(|dir| tree_paths.contains(dir)))
+        });
+    }
     println!("The following duplicate files have been found:");
-    for (hash, paths) in duplicates.duplicates() {
+    for (hash, paths) in groups.iter().take(top.unwrap_or(usize::MAX)) {
         println!("Hash: {}", hash);
         let size = paths.file_size();
-        for entry in paths.iter() {
-            dup_bytes += size;
-            println!("-> size: {}, file: '{}'", format_bytes(size), entry.display());
+        for entry in paths.entries() {
+            let sparse = if entry.is_sparse() { " (sparse)" } else { "" };
+            println!(
+                "-> size: {}, file: '{}'{}",
+                format_bytes(size),
+                entry.path().display(),
+                sparse
+            );
         }
     }
+    if let Some(top) = top.filter(|&top| top < groups.len()) {
+        println!("Listed {top} of {} groups of duplicates.", groups.len());
+    }
     println!("Duplicate files take up {} of space on disk.", format_bytes(dup_bytes));
+    if !duplicates.skipped().is_empty() {
+        println!("Skipped {} files.", duplicates.skipped().len());
+    }
+}
+
+fn format_directory_report(report: &DirectoryReport, top: usize) -> String {
+    let mut out = "Directories holding the most redundant data:".to_owned();
+    for dir in report.directories().iter().take(top) {
+        out += &format!(
+            "\n-> {} in {} files: '{}'",
+            format_bytes(dir.wasted_bytes()),
+            dir.redundant_files(),
+            dir.path().display()
+        );
+    }
+    out += "\nDirectories sharing the most content:";
+    for pair in report.pairs().iter().take(top) {
+        let (first, second) = pair.paths();
+        out += &format!(
+            "\n-> {} in {} files: '{}' and '{}'",
+            format_bytes(pair.shared_bytes()),
+            pair.shared_files(),
+            first.display(),
+            second.display()
+        );
+    }
+    out
+}
+
+fn format_similar_directories(similar: &[SimilarDirectories], top: usize) -> String {
+    let mut out = "Similar directories:".to_owned();
+    for pair in similar.iter().take(top) {
+        let (first, second) = pair.paths();
+        out += &format!(
+            "\n-> {:.1}%: '{}' and '{}' ({} shared, {} only in the first, {} only in the second)",
+            pair.similarity() * 100.0,
+            first.display(),
+            second.display(),
+            pair.shared(),
+            pair.first_only(),
+            pair.second_only()
+        );
+    }
+    out
+}
+
+/// Returns the files outside of `backup` that couldn't be read, and so weren't checked against the backup.
+fn unreadable_outside_backup<'a>(
+    duplicates: &'a DeduperResult,
+    backup: &[PathBuf],
+) -> Vec<&'a SkippedEntry> {
+    duplicates
+        .skipped()
+        .iter()
+        .filter(|entry| matches!(entry.reason(), SkipReason::Io(_)))
+        .filter(|entry| !backup.iter().any(|dir| entry.path().starts_with(dir)))
+        .collect()
+}
+
+fn format_missing_from_backup(
+    duplicates: &DeduperResult,
+    backup: &[PathBuf],
+    lower_limit: u64,
+) -> String {
+    let missing = duplicates.missing_from_reference();
+    let unreadable = unreadable_outside_backup(duplicates, backup);
+    let mut out = String::new();
+    if !missing.is_empty() {
+        out += "The following files have no copy in the backup:";
+        for entry in &missing {
+            out += &format!(
+                "\n-> size: {}, file: '{}'",
+                format_bytes(entry.size()),
+                entry.path().display()
+            );
+        }
+        let bytes = missing.iter().map(|entry| entry.size()).sum();
+        out += &format!(
+            "\n{} files ({}) are missing from the backup.",
+            missing.len(),
+            format_bytes(bytes)
+        );
+    }
+    if !unreadable.is_empty() {
+        if !out.is_empty() {
+            out += "\n";
+        }
+        out += "The following files couldn't be read, so they weren't checked:";
+        for entry in &unreadable {
+            out += &format!("\n-> file: '{}': {}", entry.path().display(), entry.reason());
+        }
+    }
+    if out.is_empty() {
+        out += "All files have a copy in the backup.";
+    }
+    if lower_limit > 0 {
+        out += &format!(
+            "\nFiles under {} weren't checked (see --lower-limit).",
+            format_bytes(lower_limit)
+        );
+    }
+    out
+}
+
+fn format_manifest_diff(old: &Manifest, new: &Manifest) -> String {
+    let diff = old.diff(new);
+    let mut out = String::new();
+    for (before, after) in diff.moved() {
+        out += &format!(
+            "Moved: '{}' -> '{}'\n",
+            old.path(before).display(),
+            new.path(after).display()
+        );
+    }
+    for (before, after) in diff.modified() {
+        out += &format!(
+            "Modified: '{}' ({} -> {})\n",
+            new.path(after).display(),
+            format_bytes(before.size()),
+            format_bytes(after.size())
+        );
+    }
+    for entry in diff.added() {
+        out +=
+            &format!("Added: '{}' ({})\n", new.path(entry).display(), format_bytes(entry.size()));
+    }
+    for entry in diff.removed() {
+        out +=
+            &format!("Removed: '{}' ({})\n", old.path(entry).display(), format_bytes(entry.size()));
+    }
+    out += &format!(
+        "{} added, {} removed, {} modified, {} moved.",
+        diff.added().len(),
+        diff.removed().len(),
+        diff.modified().len(),
+        diff.moved().len()
+    );
+    let lower_limit = old.lower_limit().max(new.lower_limit());
+    if lower_limit > 0 {
+        out += &format!(
+            "\nFiles under {} weren't compared (see --lower-limit).",
+            format_bytes(lower_limit)
+        );
+    }
+    if old.skipped() > 0 || new.skipped() > 0 {
+        out += &format!(
+            "\n{} files were skipped by the older search, and {} by the newer one, so they weren't compared.",
+            old.skipped(),
+            new.skipped()
+        );
+    }
+    out
+}
+
+fn read_manifest(path: &Path) -> anyhow::Result<Manifest> {
+    let file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("failed to open '{}': {}", path.display(), e))?;
+    Manifest::read_from(BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("failed to read '{}': {}", path.display(), e))
+}
+
+fn save_manifest(
+    roots: &[PathBuf],
+    duplicates: &DeduperResult,
+    lower_limit: u64,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let manifest = Manifest::new(roots, duplicates)
+        .map_err(|e| anyhow::anyhow!("failed to save a manifest to '{}': {}", path.display(), e))?
+        .with_lower_limit(lower_limit);
+    let file = File::create(path)
+        .map_err(|e| anyhow::anyhow!("failed to create '{}': {}", path.display(), e))?;
+    manifest
+        .write_to(file)
+        .map_err(|e| anyhow::anyhow!("failed to write '{}': {}", path.display(), e))?;
+    eprintln!("Saved the hashes of {} files to '{}'.", manifest.entries().len(), path.display());
+    Ok(())
+}
+
+fn format_scan_stats(stats: &ScanStats, format: StatsFormat) -> String {
+    let eliminated = (
+        stats.eliminated_by_size(),
+        stats.eliminated_by_partial_hash(),
+        stats.eliminated_by_full_hash(),
+    );
+    match format {
+        StatsFormat::Text => {
+            let mut out = format!("Walk: {:.3}s\n", stats.walk_duration().as_secs_f64());
+            for (i, round) in stats.rounds().iter().enumerate() {
+                out += &format!(
+                    "Round {}: {:.3}s, {} files, {} read\n",
+                    i + 1,
+                    round.duration().as_secs_f64(),
+                    round.candidates(),
+                    format_bytes(round.bytes_read())
+                );
+            }
+            out += &format!(
+                "Hashing: {:.3}s, {} read, {} files opened\n",
+                stats.hash_duration().as_secs_f64(),
+                format_bytes(stats.bytes_read()),
+                stats.files_opened()
+            );
+            out += &format!(
+                "Eliminated: {} by size, {} by partial hash, {} by full hash",
+                eliminated.0, eliminated.1, eliminated.2
+            );
+            out
+        }
+        StatsFormat::Json => {
+            let rounds: Vec<_> = stats
+                .rounds()
+                .iter()
+                .map(|round| {
+                    format!(
+                        r#"{{"duration_secs":{:.6},"candidates":{},"bytes_read":{}}}"#,
+                        round.duration().as_secs_f64(),
+                        round.candidates(),
+                        round.bytes_read()
+                    )
+                })
+                .collect();
+            format!(
+                concat!(
+                    r#"{{"walk_duration_secs":{:.6},"hash_duration_secs":{:.6},"bytes_read":{},"#,
+                    r#""files_opened":{},"eliminated_by_size":{},"eliminated_by_partial_hash":{},"#,
+                    r#""eliminated_by_full_hash":{},"rounds":[{}]}}"#
+                ),
+                stats.walk_duration().as_secs_f64(),
+                stats.hash_duration().as_secs_f64(),
+                stats.bytes_read(),
+                stats.files_opened(),
+                eliminated.0,
+                eliminated.1,
+                eliminated.2,
+                rounds.join(",")
+            )
+        }
+    }
+}
+
+fn print_skipped(duplicates: &DeduperResult) {
+    for entry in duplicates.skipped() {
+        println!("Skipped '{}': {}", entry.path().display(), entry.reason());
+    }
 }
 
 fn remove_file(path: &std::path::Path) {
@@ -150,7 +734,7 @@ fn interactive_removal(
     for (hash, entries) in duplicates.duplicates() {
         let size = entries.file_size();
         println!("Hash: {}", hash);
-        let mut entries = entries.iter().map(|e| e.to_owned()).collect::<Vec<_>>();
+        let mut entries = entries.iter().collect::<Vec<_>>();
         entries.sort();
         let mut i = 0;
         let mut j = 1;
@@ -201,7 +785,7 @@ fn interactive_removal(
 
 fn same_filename_removal(duplicates: DeduperResult) {
     for (_, entries) in duplicates.duplicates() {
-        let mut entries = entries.iter().map(|e| e.to_owned()).collect::<Vec<_>>();
+        let mut entries = entries.iter().collect::<Vec<_>>();
         entries.sort();
         for dup_path in &entries[1..] {
             if dup_path.file_name() == entries[0].file_name() {
@@ -239,7 +823,7 @@ fn same_content(p1: &Path, p2: &Path) -> io::Result<bool> {
 
 fn paranoid_removal(duplicates: DeduperResult) {
     for (_, entries) in duplicates.duplicates() {
-        let mut entries = entries.iter().map(|e| e.to_owned()).collect::<Vec<_>>();
+        let mut entries = entries.iter().collect::<Vec<_>>();
         entries.sort();
         for dup_path in &entries[1..] {
             match same_content(&entries[0], dup_path) {
@@ -263,38 +847,252 @@ fn paranoid_removal(duplicates: DeduperResult) {
     }
 }
 
+/// What the progress line shows.
 #[derive(Default)]
+struct Progress {
+    /// The directories and files walked so far, while the walk is running.
+    walk: Option<(u64, u64)>,
+    round: u32,
+    /// The candidates left in the current round.
+    candidates: usize,
+    /// How many bytes the current round has to get through, at most.
+    round_bytes: u64,
+    /// The bytes hashed so far, over all rounds.
+    hashed: u64,
+    /// The bytes that didn't have to be hashed because their file was eliminated early, over all rounds.
+    saved: u64,
+    /// `hashed + saved` at the start of the current round.
+    round_start: u64,
+    /// When the first round started.
+    started: Option<Instant>,
+}
+
+impl Progress {
+    fn render(&self, now: Instant) -> String {
+        if let Some((dirs, files)) = self.walk {
+            return format!("Walking: {dirs} directories, {files} files");
+        }
+
+        let done = (self.hashed + self.saved - self.round_start).min(self.round_bytes);
+        let ratio = if self.round_bytes == 0 { 1.0 } else { done as f64 / self.round_bytes as f64 };
+        let width = 20;
+        let filled = (ratio * width as f64) as usize;
+        let elapsed = self.started.map_or(0.0, |started| (now - started).as_secs_f64());
+        let throughput = if elapsed > 0.0 { self.hashed as f64 / elapsed } else { 0.0 };
+        let eta = if throughput > 0.0 {
+            let secs = ((self.round_bytes - done) as f64 / throughput) as u64;
+            format!("{}:{:02}", secs / 60, secs % 60)
+        } else {
+            "?".to_owned()
+        };
+
+        format!(
+            "Round {} [{}{}] {} candidates, {} hashed ({}/s), {} saved, ETA {}",
+            self.round,
+            "#".repeat(filled),
+            "-".repeat(width - filled),
+            self.candidates,
+            format_bytes(self.hashed),
+            format_bytes(throughput as u64),
+            format_bytes(self.saved),
+            eta
+        )
+    }
+}
+
+/// Draws a progress line on stderr, if it is a terminal.
 struct FindHook {
-    last_round: AtomicUsize,
-    count: AtomicUsize,
+    enabled: bool,
+    progress: Mutex<(Progress, Option<Instant>)>,
+}
+
+impl FindHook {
+    /// How often the progress line is redrawn.
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+    fn new() -> Self {
+        Self { enabled: io::stderr().is_terminal(), progress: Mutex::default() }
+    }
+
+    /// Update the progress with `f`, and redraw it if it wasn't drawn recently (or if `force` is set).
+    fn update(&self, force: bool, f: impl FnOnce(&mut Progress)) {
+        if !self.enabled {
+            return;
+        }
+        let mut guard = self.progress.lock().unwrap();
+        let (progress, last_draw) = &mut *guard;
+        f(progress);
+        let now = Instant::now();
+        if force || last_draw.is_none_or(|last| now - last >= Self::REDRAW_INTERVAL) {
+            *last_draw = Some(now);
+            eprint!("\r\x1b[K{}", progress.render(now));
+        }
+    }
+}
+
+impl Drop for FindHook {
+    fn drop(&mut self) {
+        // clear the progress line, so that it doesn't mix with the results
+        if self.enabled {
+            eprint!("\r\x1b[K");
+        }
+    }
 }
 
 impl duped::DeduperFindHook for FindHook {
-    fn files_selected(&self, size: usize) {
-        self.last_round.store(size, Ordering::Relaxed);
-        self.count.store(0, Ordering::Relaxed);
-        print!("0/{size}\r");
+    fn walk_progress(&self, dirs: u64, files: u64, done: bool) {
+        self.update(done, |p| p.walk = (!done).then_some((dirs, files)));
+    }
+
+    fn round_started(&self, round: u32, candidates: usize, bytes_left: u64) {
+        self.update(true, |p| {
+            p.walk = None;
+            p.round = round;
+            p.candidates = candidates;
+            p.round_bytes = bytes_left;
+            p.round_start = p.hashed + p.saved;
+            p.started.get_or_insert_with(Instant::now);
+        });
     }
 
-    fn entry_processed(&self, _: duped::blake3::Hash, _: &duped::FileEntry) {
-        let old = self.count.fetch_add(1, Ordering::Relaxed) + 1;
-        let n = self.last_round.load(Ordering::Relaxed);
-        print!("{old}/{n}\r");
+    fn round_finished(&self, _: u32, _: usize, remaining: usize) {
+        self.update(true, |p| p.candidates = remaining);
+    }
+
+    fn bytes_hashed(&self, bytes: u64) {
+        self.update(false, |p| p.hashed += bytes);
+    }
+
+    fn candidates_eliminated(&self, count: usize, bytes_saved: u64) {
+        self.update(false, |p| {
+            p.candidates = p.candidates.saturating_sub(count);
+            p.saved += bytes_saved;
+        });
     }
 }
 
+/// How many times the limits should be doubled (or halved, if negative), based on the signals received so far.
+#[cfg(unix)]
+static THROTTLE_ADJUSTMENT: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_throttle_signal(signal: libc::c_int) {
+    let change = if signal == libc::SIGUSR2 { 1 } else { -1 };
+    THROTTLE_ADJUSTMENT.fetch_add(change, Ordering::Relaxed);
+}
+
+/// Halve the limits of `throttle` on SIGUSR1, and double them on SIGUSR2.
+#[cfg(unix)]
+fn adjust_throttle_on_signals(throttle: Throttle) {
+    // SAFETY: the handler only touches an atomic, which is async-signal-safe.
+    unsafe {
+        let handler = on_throttle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGUSR1, handler);
+        libc::signal(libc::SIGUSR2, handler);
+    }
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let adjustment = THROTTLE_ADJUSTMENT.swap(0, Ordering::Relaxed);
+        if adjustment == 0 {
+            continue;
+        }
+        let adjust = |rate: u64| {
+            let rate = if adjustment > 0 {
+                rate.saturating_mul(1 << adjustment.min(32))
+            } else {
+                rate >> adjustment.unsigned_abs().min(63)
+            };
+            rate.max(1)
+        };
+        throttle.set_bytes_per_sec(throttle.bytes_per_sec().map(adjust));
+        throttle.set_files_per_sec(throttle.files_per_sec().map(adjust));
+        eprintln!(
+            "Limits changed: {} per second, {} files per second.",
+            throttle.bytes_per_sec().map(format_bytes).unwrap_or_else(|| "unlimited".into()),
+            throttle.files_per_sec().map(|r| r.to_string()).unwrap_or_else(|| "unlimited".into()),
+        );
+    });
+}
+
+#[cfg(not(unix))]
+fn adjust_throttle_on_signals(_: Throttle) {}
+
 fn main() -> anyhow::Result<()> {
     let args = match parse_args()? {
-        Some(args) => args,
+        Some(Command::Find(args)) => args,
+        Some(Command::DiffManifests(old, new)) => {
+            println!("{}", format_manifest_diff(&read_manifest(&old)?, &read_manifest(&new)?));
+            return Ok(());
+        }
         None => return Ok(()),
     };
-    println!("Directories: {:?}", args.deduper.roots());
-    let stats = args.deduper.find(args.content_limit, FindHook::default())?;
+    if let Some(throttle) = args.throttle {
+        adjust_throttle_on_signals(throttle);
+    }
+    let html = args.format == ReportFormat::Html && args.remove.is_none();
+    if !html && args.backup.is_empty() {
+        println!("Directories: {:?}", args.deduper.roots());
+    }
+    let mut stats = args.deduper.find(args.filter, FindHook::new())?;
+    stats.set_root_filter(args.root_filter);
+    if let Some(path) = &args.save_manifest {
+        save_manifest(args.deduper.roots(), &stats, args.lower_limit, path)?;
+    }
+    if let Some(path) = &args.stats_json {
+        let json = format_scan_stats(stats.stats(), StatsFormat::Json) + "\n";
+        std::fs::write(path, json)
+            .map_err(|e| anyhow::anyhow!("failed to write '{}': {}", path.display(), e))?;
+    }
+    if !args.backup.is_empty() {
+        if args.list_skipped {
+            print_skipped(&stats);
+        }
+        println!("{}", format_missing_from_backup(&stats, &args.backup, args.lower_limit));
+        if !stats.skipped().is_empty() {
+            println!("Skipped {} files.", stats.skipped().len());
+        }
+        if args.stats {
+            println!("{}", format_scan_stats(stats.stats(), StatsFormat::Text));
+        }
+        if !stats.missing_from_reference().is_empty()
+            || !unreadable_outside_backup(&stats, &args.backup).is_empty()
+        {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if html {
+        print!("{}", html::render(&stats, args.sort));
+        return Ok(());
+    }
+    if args.list_skipped {
+        print_skipped(&stats);
+    }
+    let directory_report = args
+        .by_directory
+        .then(|| format_directory_report(&stats.directory_report(), args.top.unwrap_or(10)));
+    let similar = args.similar.map(|min_similarity| {
+        format_similar_directories(
+            &stats.similar_directories(min_similarity),
+            args.top.unwrap_or(10),
+        )
+    });
+    let scan_stats = args.stats.then(|| format_scan_stats(stats.stats(), StatsFormat::Text));
     match args.remove {
         Some(RemovalKind::Interactive) => interactive_removal(stats, std::io::stdin().lock())?,
         Some(RemovalKind::SameFilename) => same_filename_removal(stats),
         Some(RemovalKind::Paranoid) => paranoid_removal(stats),
-        None => print_stats(stats),
+        None => print_stats(stats, args.sort, args.top, args.flat),
+    }
+    if let Some(directory_report) = directory_report {
+        println!("{directory_report}");
+    }
+    if let Some(similar) = similar {
+        println!("{similar}");
+    }
+    if let Some(scan_stats) = scan_stats {
+        println!("{scan_stats}");
     }
     Ok(())
 }
@@ -303,6 +1101,7 @@ fn main() -> anyhow::Result<()> {
 mod tests {
     use super::*;
 
+    use duped::{FileKind, SkipReason};
     use std::{fs::File, io::Cursor, path::Path};
     use tempfile::TempDir;
 
@@ -394,6 +1193,607 @@ mod tests {
         do_check(ctx, &files);
     }
 
+    #[test]
+    fn excluded_files_are_not_reported() {
+        let dir = build_nested_tree(&[
+            ("a", &[("a1", b"a1"), ("b.tmp", b"b")]),
+            ("node_modules", &[("a2", b"a1"), ("b.tmp", b"b")]),
+        ]);
+        let filter: Filter = (
+            ContentLimit::no_limit(),
+            GlobFilter::new(&[] as &[&str], ["*.tmp", "node_modules/"]).unwrap(),
+            RegexFilter::new(&[] as &[&str], &[] as &[&str]).unwrap(),
+        );
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(filter, duped::NoopFindHook).unwrap();
+        assert_eq!(stats.duplicates().count(), 0);
+        assert_eq!(stats.hashes().len(), 1);
+    }
+
+    #[test]
+    fn ignore_files_are_respected() {
+        let dir = build_nested_tree(&[
+            ("a", &[("a1", b"a1"), ("b", b"b"), (".gitignore", b"a1\nb\n.gitignore\n")]),
+            ("b", &[("a2", b"a1"), ("b", b"b"), (".dupedignore", b"*\n")]),
+            ("c", &[("b", b"b"), (".dupedignore", b"*\n!b\n")]),
+        ]);
+        let stats = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .respect_ignore_files(true)
+            .build()
+            .find(ContentLimit::no_limit(), duped::NoopFindHook)
+            .unwrap();
+        let files: Vec<_> = stats.hashes().values().flat_map(|e| e.iter()).collect();
+        assert_eq!(files, [dir.path().join("c/b")]);
+    }
+
+    #[test]
+    fn hidden_and_deep_files_are_skipped() {
+        let dir = build_nested_tree(&[
+            ("a", &[("a1", b"a1"), (".a2", b"a1")]),
+            (".b", &[("a3", b"a1")]),
+            ("c", &[]),
+            ("c/d", &[("a4", b"a1")]),
+        ]);
+        let stats = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .skip_hidden(true)
+            .max_depth(2)
+            .build()
+            .find(ContentLimit::no_limit(), duped::NoopFindHook)
+            .unwrap();
+        let files: Vec<_> = stats.hashes().values().flat_map(|e| e.iter()).collect();
+        assert_eq!(files, [dir.path().join("a/a1")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        build_tree(dir.path(), &[("a", b"a"), ("a2", b"a")]);
+        std::os::unix::fs::symlink(dir.path().join("a"), dir.path().join("link")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("socket")).unwrap();
+        let stats = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .build()
+            .find(ContentLimit::no_limit(), duped::NoopFindHook)
+            .unwrap();
+        assert_eq!(stats.duplicates().count(), 1);
+        let mut skipped: Vec<_> =
+            stats.skipped().iter().map(|e| (e.path().to_owned(), e.reason())).collect();
+        skipped.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            skipped,
+            [
+                (dir.path().join("link"), SkipReason::NotRegularFile(FileKind::Symlink)),
+                (dir.path().join("socket"), SkipReason::NotRegularFile(FileKind::Socket)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stopping_the_walk_returns_partial_results() {
+        let dir = tempfile::tempdir().unwrap();
+        build_tree(dir.path(), &[("a", b"a"), ("a2", b"a"), ("a3", b"a")]);
+        let mut seen = 0;
+        let filter = |_: &Path, _: &std::fs::Metadata| {
+            seen += 1;
+            if seen > 2 {
+                duped::FilterAction::Break(())
+            } else {
+                duped::FilterAction::Continue(duped::FileAction::Include)
+            }
+        };
+        let stats = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .walk_threads(4)
+            .build()
+            .find(filter, duped::NoopFindHook)
+            .unwrap();
+        assert!(stats.is_partial());
+        let (_, entries) = stats.duplicates().next().unwrap();
+        assert_eq!(entries.iter().count(), 2);
+    }
+
+    #[test]
+    fn cache_modes_find_the_same_duplicates() {
+        // direct reads are not supported by tmpfs, which `/tmp` often is, so the files are created next to the test
+        // binary, under the target directory
+        let exe = std::env::current_exe().unwrap();
+        let dir = tempfile::tempdir_in(exe.parent().unwrap()).unwrap();
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        build_tree(
+            dir.path(),
+            &[("a", &big), ("a2", &big), ("b", &big[1..]), ("c", b"c"), ("c2", b"c")],
+        );
+        for mode in [CacheMode::Cached, CacheMode::DropBehind, CacheMode::Direct] {
+            let deduper =
+                duped::Deduper::builder(vec![dir.path().to_owned()]).cache_mode(mode).build();
+            let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+            assert_eq!(stats.duplicates().count(), 2);
+        }
+    }
+
+    #[test]
+    fn spilled_candidates_find_the_same_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = tempfile::tempdir().unwrap();
+        let files: Vec<_> = (0..20u8).map(|i| (format!("{i}"), vec![i % 5; 10_000])).collect();
+        let files: Vec<_> = files.iter().map(|(name, data)| (name.as_str(), &data[..])).collect();
+        build_tree(dir.path(), &files);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .spill_candidates(spill.path().to_owned(), 3)
+            .build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+        assert_eq!(stats.duplicates().count(), 5);
+        assert!(stats.duplicates().all(|(_, entries)| entries.iter().count() == 4));
+        assert_eq!(std::fs::read_dir(spill.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn scan_stats_are_formatted() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = vec![1; 4096];
+        let mut other = big.clone();
+        other[4095] = 2;
+        build_tree(
+            dir.path(),
+            &[("a", &big), ("b", &big), ("c", &other), ("d", b"unique size"), ("e", b"x")],
+        );
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .probe_schedule(duped::ProbeSchedule::disabled())
+            .build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+        let stats = stats.stats();
+
+        // `d` and `e` have unique sizes, and `c` is only told apart from `a` and `b` once it is hashed entirely
+        let json = format_scan_stats(stats, StatsFormat::Json);
+        assert!(json.contains(
+            r#""bytes_read":12288,"files_opened":3,"eliminated_by_size":2,"eliminated_by_partial_hash":0,"eliminated_by_full_hash":1,"#
+        ), "{json}");
+        assert_eq!(json.matches("\"candidates\":3").count(), 1, "{json}");
+        let text = format_scan_stats(stats, StatsFormat::Text);
+        assert!(
+            text.ends_with("Eliminated: 2 by size, 0 by partial hash, 1 by full hash"),
+            "{text}"
+        );
+    }
+
+    #[test]
+    fn progress_events_are_reported() {
+        #[derive(Clone, Default)]
+        struct Events(std::sync::Arc<Mutex<Vec<String>>>);
+
+        impl duped::DeduperFindHook for Events {
+            fn walk_progress(&self, dirs: u64, files: u64, done: bool) {
+                if done {
+                    self.0.lock().unwrap().push(format!("walked {dirs} {files}"));
+                }
+            }
+
+            fn round_started(&self, round: u32, candidates: usize, bytes_left: u64) {
+                self.0.lock().unwrap().push(format!("started {round} {candidates} {bytes_left}"));
+            }
+
+            fn round_finished(&self, round: u32, eliminated: usize, remaining: usize) {
+                self.0.lock().unwrap().push(format!("finished {round} {eliminated} {remaining}"));
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        build_tree(dir.path(), &[("a", b"aa"), ("b", b"aa"), ("c", b"ab"), ("d", b"abc")]);
+        let events = Events::default();
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        deduper.find(ContentLimit::no_limit(), events.clone()).unwrap();
+        let events = events.0.lock().unwrap();
+        assert_eq!(events[..2], ["walked 0 4", "started 1 4 9"]);
+        // `d` is the only file of its size, and the others are small enough to be hashed in a single round
+        assert_eq!(events[2..], ["finished 1 1 0"]);
+    }
+
+    #[test]
+    fn progress_is_rendered() {
+        let now = Instant::now();
+        let walk = Progress { walk: Some((3, 40)), ..Default::default() };
+        assert_eq!(walk.render(now), "Walking: 3 directories, 40 files");
+
+        let round = Progress {
+            round: 2,
+            candidates: 7,
+            round_bytes: 4096,
+            hashed: 3072,
+            saved: 1024,
+            round_start: 2048,
+            started: Some(now - Duration::from_secs(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            round.render(now),
+            "Round 2 [##########----------] 7 candidates, 3.00 KiB hashed (1.50 KiB/s), 1.00 KiB saved, ETA 0:01"
+        );
+    }
+
+    #[test]
+    fn duplicates_are_sorted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (small, large) = (vec![1; 100], vec![2; 250]);
+        build_tree(
+            dir.path(),
+            &[
+                ("s3", &small),
+                ("s1", &small),
+                ("s2", &small),
+                ("s4", &small),
+                ("l2", &large),
+                ("l1", &large),
+                ("m1", b"abc"),
+                ("m2", b"abc"),
+            ],
+        );
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+        let order = |order| {
+            let groups = stats.sorted_duplicates(order);
+            groups
+                .iter()
+                .map(|(_, entries)| {
+                    let names: Vec<_> = entries
+                        .iter()
+                        .map(|p| p.file_name().unwrap().to_str().unwrap().to_owned())
+                        .collect();
+                    names.join(",")
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the small files waste 300 bytes, and the large ones 250
+        assert_eq!(order(DuplicateOrder::WastedBytes), ["s1,s2,s3,s4", "l1,l2", "m1,m2"]);
+        assert_eq!(order(DuplicateOrder::Size), ["l1,l2", "s1,s2,s3,s4", "m1,m2"]);
+        assert_eq!(order(DuplicateOrder::FirstPath), ["l1,l2", "m1,m2", "s1,s2,s3,s4"]);
+        assert_eq!(order(DuplicateOrder::Count), ["s1,s2,s3,s4", "l1,l2", "m1,m2"]);
+    }
+
+    #[test]
+    fn directory_report_attributes_waste() {
+        let dir = build_nested_tree(&[
+            ("a", &[("x", b"xxxx"), ("y", b"yyyyyy")]),
+            ("b", &[("x", b"xxxx"), ("y", b"yyyyyy")]),
+            ("b/c", &[("x", b"xxxx")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+        let report = stats.directory_report();
+
+        let relative = |path: &Path| path.strip_prefix(dir.path()).unwrap().to_owned();
+        let dirs: Vec<_> = report
+            .directories()
+            .iter()
+            .map(|d| (relative(d.path()), d.wasted_bytes(), d.redundant_files()))
+            .collect();
+        // `a` holds the originals, so all the waste is under `b`, and attributed to the directories above it
+        assert_eq!(dirs[0], (PathBuf::new(), 14, 3));
+        assert_eq!(dirs[1..], [("b".into(), 14, 3), ("b/c".into(), 4, 1)]);
+
+        let pairs: Vec<_> = report
+            .pairs()
+            .iter()
+            .map(|p| (relative(p.paths().0), relative(p.paths().1), p.shared_bytes()))
+            .collect();
+        assert_eq!(
+            pairs,
+            [
+                ("a".into(), "b".into(), 10),
+                ("a".into(), "b/c".into(), 4),
+                ("b".into(), "b/c".into(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_trees_are_found() {
+        let copy: &Files<'_> = &[("x", b"xxxx"), ("y", b"yyyyyy")];
+        let dir = build_nested_tree(&[
+            ("a", &[]),
+            ("a/data", copy),
+            ("a/data/sub", &[("z", b"zz")]),
+            ("b", &[]),
+            ("b/data", copy),
+            ("b/data/sub", &[("z", b"zz")]),
+            // same files, but under other names
+            ("c", &[("x2", b"xxxx"), ("y", b"yyyyyy")]),
+            // an extra file
+            ("d", &[("x", b"xxxx"), ("y", b"yyyyyy"), ("w", b"w")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+        let trees = stats.duplicate_trees();
+
+        // `a` and `b` only contain `data`, so they are identical too, and `data` is not reported on its own
+        let relative = |path: &PathBuf| path.strip_prefix(dir.path()).unwrap().to_owned();
+        let paths: Vec<Vec<_>> =
+            trees.iter().map(|tree| tree.paths().iter().map(relative).collect()).collect();
+        assert_eq!(paths, [[PathBuf::from("a"), PathBuf::from("b")]]);
+        assert_eq!((trees[0].size(), trees[0].files(), trees[0].wasted_bytes()), (12, 3, 12));
+        assert!(trees[0].contains(&dir.path().join("b/data/sub/z")));
+        assert!(!trees[0].contains(&dir.path().join("c/y")));
+    }
+
+    #[test]
+    fn widespread_files_count_towards_similarity() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..70 {
+            let sub = dir.path().join(i.to_string());
+            std::fs::create_dir(&sub).unwrap();
+            build_tree(&sub, &[("LICENSE", b"license")]);
+        }
+        build_tree(&dir.path().join("0"), &[("a", b"shared by two")]);
+        build_tree(&dir.path().join("1"), &[("b", b"shared by two")]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        // the license is in too many directories to find pairs through it, but it is still shared by the pair
+        let similar = stats.similar_directories(0.5);
+        assert_eq!(similar.len(), 1);
+        let pair = &similar[0];
+        assert_eq!((pair.shared(), pair.first_only(), pair.second_only()), (2, 0, 0));
+        assert_eq!(pair.similarity(), 1.0);
+    }
+
+    #[test]
+    fn trees_with_filtered_files_are_not_reported() {
+        let dir = build_nested_tree(&[
+            ("a", &[("big", b"large enough"), ("small", b"1")]),
+            // only the file that is too small to be searched differs
+            ("b", &[("big", b"large enough"), ("small", b"2")]),
+            ("c", &[]),
+            ("c/sub", &[("big", b"large enough")]),
+            ("d", &[]),
+            ("d/sub", &[("big", b"large enough")]),
+            ("d/excluded", &[("other", b"large, but excluded")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let filter = (
+            ContentLimit::no_limit().with_lower_limit(2),
+            GlobFilter::new(Vec::<String>::new(), vec!["excluded/".to_owned()]).unwrap(),
+        );
+        let stats = deduper.find(filter, duped::NoopFindHook).unwrap();
+        assert_eq!(stats.duplicates().count(), 1);
+
+        // `c` and `d` aren't identical either, since `d` has a directory that was excluded
+        let relative = |path: &PathBuf| path.strip_prefix(dir.path()).unwrap().to_owned();
+        let paths: Vec<Vec<_>> = stats
+            .duplicate_trees()
+            .iter()
+            .map(|tree| tree.paths().iter().map(relative).collect())
+            .collect();
+        assert_eq!(paths, [[PathBuf::from("c/sub"), PathBuf::from("d/sub")]]);
+    }
+
+    #[test]
+    fn searches_fail_if_the_collector_exits() {
+        struct PanickingHook;
+
+        impl duped::DeduperFindHook for PanickingHook {
+            fn entry_processed(&self, _: duped::blake3::Hash, _: &duped::FileEntry) {
+                panic!("the collector calls this hook");
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        build_tree(dir.path(), &[("a", b"unique")]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let error = deduper.find(ContentLimit::no_limit(), PanickingHook).unwrap_err();
+        assert_eq!(error.to_string(), "collector thread exited");
+    }
+
+    #[test]
+    fn trees_with_pruned_entries_are_not_reported() {
+        let dir = build_nested_tree(&[
+            ("a", &[("f", b"same")]),
+            // too deep to be walked
+            ("a/sub", &[("x", b"one")]),
+            ("b", &[("f", b"same")]),
+            ("b/sub", &[("x", b"two, different")]),
+            ("c", &[("f", b"also the same"), (".hidden", b"one")]),
+            ("d", &[("f", b"also the same"), (".hidden", b"two, different")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()])
+            .max_depth(2)
+            .skip_hidden(true)
+            .build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        assert_eq!(stats.duplicates().count(), 2);
+        assert!(stats.duplicate_trees().is_empty());
+    }
+
+    #[test]
+    fn similar_directories_are_found() {
+        let dir = build_nested_tree(&[
+            ("a", &[("1", b"1"), ("2", b"22"), ("3", b"333"), ("4", b"4444")]),
+            // one file was edited, and one was renamed
+            ("b", &[("1", b"1"), ("two", b"22"), ("3", b"333"), ("4", b"edited")]),
+            ("c", &[("1", b"1"), ("5", b"55555")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![dir.path().to_owned()]).build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        let name = |path: &Path| path.file_name().unwrap().to_str().unwrap().to_owned();
+        let similar: Vec<_> = stats
+            .similar_directories(0.2)
+            .iter()
+            .map(|pair| {
+                let (first, second) = pair.paths();
+                (name(first), name(second), pair.shared(), pair.first_only(), pair.second_only())
+            })
+            .collect();
+        assert_eq!(
+            similar,
+            [
+                ("a".into(), "b".into(), 3, 1, 1),
+                ("a".into(), "c".into(), 1, 3, 1),
+                ("b".into(), "c".into(), 1, 3, 1)
+            ]
+        );
+        assert_eq!(stats.similar_directories(0.5).len(), 1);
+        assert_eq!(stats.similar_directories(0.5)[0].similarity(), 0.6);
+    }
+
+    #[test]
+    fn files_missing_from_backup_are_listed() {
+        let source = build_nested_tree(&[
+            ("photos", &[("a", b"backed up"), ("b", b"twice"), ("c", b"twice")]),
+            ("photos/new", &[("d", b"moved in the backup"), ("e", b"not backed up")]),
+        ]);
+        let backup = build_nested_tree(&[
+            (
+                "old",
+                &[("a", b"backed up"), ("f", b"only in the backup"), ("g", b"only in the backup")],
+            ),
+            ("old/moved", &[("d2", b"moved in the backup")]),
+        ]);
+        let deduper = duped::Deduper::builder(vec![source.path().to_owned()])
+            .reference_roots(vec![backup.path().to_owned()])
+            .build();
+        let stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        let missing: Vec<_> = stats.missing_from_reference().iter().map(|e| e.path()).collect();
+        let photos = source.path().join("photos");
+        assert_eq!(missing, [photos.join("b"), photos.join("c"), photos.join("new/e")]);
+        assert_eq!(
+            format_missing_from_backup(&stats, &[backup.path().to_owned()], 0),
+            format!(
+                "The following files have no copy in the backup:\n-> size: 5 B, file: '{}'\n\
+                 -> size: 5 B, file: '{}'\n-> size: 13 B, file: '{}'\n\
+                 3 files (23 B) are missing from the backup.",
+                photos.join("b").display(),
+                photos.join("c").display(),
+                photos.join("new/e").display()
+            )
+        );
+    }
+
+    #[test]
+    fn unreadable_files_are_not_backed_up() {
+        /// Removes the files it includes, so that they can't be opened to be hashed.
+        struct RemoveFiles;
+
+        impl duped::DeduperFileFilter for RemoveFiles {
+            fn handle_file(&mut self, path: &Path, _: &std::fs::Metadata) -> duped::FilterAction {
+                if path.ends_with("gone") {
+                    std::fs::remove_file(path).unwrap();
+                }
+                duped::FilterAction::Continue(duped::FileAction::Include)
+            }
+        }
+
+        let source = tempfile::tempdir().unwrap();
+        build_tree(source.path(), &[("a", b"backed up"), ("gone", b"same size")]);
+        let backup = tempfile::tempdir().unwrap();
+        build_tree(backup.path(), &[("a", b"backed up"), ("b", b"not equal")]);
+        let backup = vec![backup.path().to_owned()];
+        let deduper = duped::Deduper::builder(vec![source.path().to_owned()])
+            .reference_roots(backup.clone())
+            .build();
+        let stats = deduper.find(RemoveFiles, duped::NoopFindHook).unwrap();
+
+        assert!(stats.missing_from_reference().is_empty());
+        assert_eq!(
+            format_missing_from_backup(&stats, &backup, 1024),
+            format!(
+                "The following files couldn't be read, so they weren't checked:\n\
+                 -> file: '{}': i/o error (entity not found)\n\
+                 Files under 1.00 KiB weren't checked (see --lower-limit).",
+                source.path().join("gone").display()
+            )
+        );
+    }
+
+    #[test]
+    fn manifests_are_diffed() {
+        let tree = build_nested_tree(&[
+            ("docs", &[("a", b"unchanged"), ("b", b"before"), ("c", b"renamed"), ("d", b"gone")]),
+            ("old", &[("e", b"moved")]),
+        ]);
+        let manifest = || {
+            let deduper = duped::Deduper::builder(vec![tree.path().to_owned()]);
+            let stats =
+                deduper.build().find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+            let error = Manifest::new(&[tree.path().to_owned()], &stats).unwrap_err();
+            assert_eq!(error.to_string(), "the files weren't all hashed entirely");
+
+            let deduper =
+                duped::Deduper::builder(vec![tree.path().to_owned()]).hash_all_files(true);
+            let stats =
+                deduper.build().find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+            let mut written = vec![];
+            Manifest::new(&[tree.path().to_owned()], &stats)
+                .unwrap()
+                .write_to(&mut written)
+                .unwrap();
+            Manifest::read_from(written.as_slice()).unwrap()
+        };
+        let old = manifest();
+
+        let docs = tree.path().join("docs");
+        std::fs::write(docs.join("b"), b"after, and longer").unwrap();
+        std::fs::rename(docs.join("c"), docs.join("c2")).unwrap();
+        std::fs::remove_file(docs.join("d")).unwrap();
+        std::fs::create_dir(tree.path().join("new")).unwrap();
+        std::fs::rename(tree.path().join("old/e"), tree.path().join("new/e")).unwrap();
+        std::fs::write(tree.path().join("new/f"), b"added").unwrap();
+        let new = manifest();
+
+        assert_eq!(
+            format_manifest_diff(&old, &new),
+            format!(
+                "Moved: '{}' -> '{}'\nMoved: '{}' -> '{}'\nModified: '{}' (6 B -> 17 B)\n\
+                 Added: '{}' (5 B)\nRemoved: '{}' (4 B)\n1 added, 1 removed, 1 modified, 2 moved.",
+                docs.join("c").display(),
+                docs.join("c2").display(),
+                tree.path().join("old/e").display(),
+                tree.path().join("new/e").display(),
+                docs.join("b").display(),
+                tree.path().join("new/f").display(),
+                docs.join("d").display(),
+            )
+        );
+        assert_eq!(format_manifest_diff(&new, &new), "0 added, 0 removed, 0 modified, 0 moved.");
+        let limited = new.clone().with_lower_limit(1024);
+        assert_eq!(
+            format_manifest_diff(&new, &limited),
+            "0 added, 0 removed, 0 modified, 0 moved.\n\
+             Files under 1.00 KiB weren't compared (see --lower-limit)."
+        );
+    }
+
+    #[test]
+    fn duplicates_are_filtered_by_root() {
+        let source = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        build_tree(source.path(), &[("a", b"backed up"), ("b", b"twice"), ("c", b"twice")]);
+        build_tree(backup.path(), &[("a", b"backed up"), ("d", b"only in the backup")]);
+        let deduper =
+            duped::Deduper::builder(vec![source.path().to_owned(), backup.path().to_owned()])
+                .build();
+        let mut stats = deduper.find(ContentLimit::no_limit(), duped::NoopFindHook).unwrap();
+
+        let groups = |stats: &DeduperResult| {
+            let groups = stats.sorted_duplicates(DuplicateOrder::FirstPath);
+            groups
+                .iter()
+                .map(|(_, entries)| {
+                    // the order of the tempdirs is random, so the entries are sorted by root instead
+                    let mut entries: Vec<_> =
+                        entries.entries().map(|e| (e.root_index(), e.path())).collect();
+                    entries.sort();
+                    entries
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(groups(&stats).len(), 2);
+        stats.set_root_filter(RootFilter::AcrossRoots);
+        assert_eq!(groups(&stats), [[(0, source.path().join("a")), (1, backup.path().join("a"))]]);
+        stats.set_root_filter(RootFilter::WithinRoot);
+        assert_eq!(groups(&stats), [[(0, source.path().join("b")), (0, source.path().join("c"))]]);
+    }
+
     #[test]
     fn same_content_works() {
         let dir = tempfile::tempdir().unwrap();
@@ -0,0 +1,97 @@
+//! Compares chunk schedules on a synthetic corpus.
+//!
+//! Run with `cargo bench -p duped --bench chunk_schedule`. The corpus is written to a temporary directory, and is
+//! hashed once before any measurement, so the numbers reflect a warm page cache.
+
+use duped::{ChunkSchedule, ContentLimit, Deduper, NoopFindHook, ProbeSchedule};
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const ITERATIONS: u32 = 5;
+
+/// A small deterministic pseudo-random generator, so that the corpus is the same on every run.
+struct XorShift(u64);
+
+impl XorShift {
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            chunk.copy_from_slice(&self.0.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// Write a corpus made of:
+/// * files with unique sizes;
+/// * groups of same-size files that differ somewhere (start, middle, or end);
+/// * groups of actual duplicates.
+fn build_corpus(dir: &Path) {
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    let sizes = [64 * KIB, 256 * KIB, MIB, 4 * MIB, 16 * MIB];
+    for (i, size) in sizes.into_iter().enumerate() {
+        let mut data = vec![0; size as usize];
+        for j in 0..8 {
+            rng.fill(&mut data);
+            std::fs::write(dir.join(format!("unique-{i}-{j}")), &data[..data.len() - j - 1])
+                .unwrap();
+        }
+
+        rng.fill(&mut data);
+        for (j, offset) in [0, data.len() / 2, data.len() - 1].into_iter().enumerate() {
+            for k in 0..4u8 {
+                data[offset] = k;
+                std::fs::write(dir.join(format!("similar-{i}-{j}-{k}")), &data).unwrap();
+            }
+        }
+
+        rng.fill(&mut data);
+        for k in 0..4 {
+            std::fs::write(dir.join(format!("duplicate-{i}-{k}")), &data).unwrap();
+        }
+    }
+}
+
+fn run(dir: &Path, chunks: ChunkSchedule, probes: ProbeSchedule) -> Duration {
+    let deduper = Deduper::builder(vec![dir.to_owned()])
+        .chunk_schedule(chunks)
+        .probe_schedule(probes)
+        .build();
+    let start = Instant::now();
+    let result = deduper.find(ContentLimit::no_limit(), NoopFindHook).unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(result.duplicates().count(), 5);
+
+    elapsed
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    build_corpus(dir.path());
+
+    let schedules = [
+        ("fixed 16 MiB", ChunkSchedule::fixed(16 * MIB)),
+        ("fixed 1 MiB", ChunkSchedule::fixed(MIB)),
+        ("fixed 64 KiB", ChunkSchedule::fixed(64 * KIB)),
+        ("geometric 4 KiB x4 to 16 MiB", ChunkSchedule::default()),
+        ("geometric 64 KiB x2 to 16 MiB", ChunkSchedule::geometric(64 * KIB, 2, 16 * MIB)),
+    ];
+    let probes = [("no probes", ProbeSchedule::disabled()), ("probes", ProbeSchedule::new())];
+
+    // warm up the page cache
+    run(dir.path(), ChunkSchedule::default(), ProbeSchedule::new());
+
+    for (probe_name, probe) in &probes {
+        for (name, schedule) in &schedules {
+            let total: Duration =
+                (0..ITERATIONS).map(|_| run(dir.path(), schedule.clone(), probe.clone())).sum();
+            println!("{name:<32} {probe_name:<10} {:>10.2?}", total / ITERATIONS);
+        }
+    }
+}
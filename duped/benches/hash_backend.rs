@@ -0,0 +1,66 @@
+//! Compares the ways files can be read while they are hashed.
+//!
+//! Run with `cargo bench -p duped --bench hash_backend --features io-uring`; without the feature, only blocking reads
+//! are measured. The corpus is written to a temporary directory (set `TMPDIR` to benchmark a particular disk), and is
+//! hashed once before any measurement, so the numbers reflect a warm page cache: the benefits of io_uring show up when
+//! reading from fast storage, rather than from memory.
+
+use duped::{ContentLimit, Deduper, HashBackend, NoopFindHook};
+
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const MIB: usize = 1024 * 1024;
+const ITERATIONS: u32 = 5;
+const GROUPS: usize = 8;
+const COPIES: usize = 4;
+
+/// Write groups of duplicates of various sizes, so that most of the corpus is read in its entirety.
+fn build_corpus(dir: &Path) {
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    for i in 0..GROUPS {
+        let mut data = vec![0; (i + 1) * MIB];
+        for chunk in data.chunks_mut(8) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            chunk.copy_from_slice(&state.to_le_bytes()[..chunk.len()]);
+        }
+        for k in 0..COPIES {
+            std::fs::write(dir.join(format!("duplicate-{i}-{k}")), &data).unwrap();
+        }
+    }
+}
+
+fn run(dir: &Path, backend: HashBackend) -> Duration {
+    let deduper = Deduper::builder(vec![dir.to_owned()]).hash_backend(backend).build();
+    let start = Instant::now();
+    let result = deduper.find(ContentLimit::no_limit(), NoopFindHook).unwrap();
+    let elapsed = start.elapsed();
+    assert_eq!(result.duplicates().count(), GROUPS);
+
+    elapsed
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    build_corpus(dir.path());
+
+    #[allow(unused_mut)]
+    let mut backends = vec![("std::fs::File".to_owned(), HashBackend::Std)];
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    for queue_depth in [4, 16, 64, 256] {
+        backends
+            .push((format!("io_uring, depth {queue_depth}"), HashBackend::IoUring { queue_depth }));
+    }
+
+    // warm up the page cache
+    run(dir.path(), HashBackend::Std);
+
+    for (name, backend) in backends {
+        let total: Duration = (0..ITERATIONS).map(|_| run(dir.path(), backend)).sum();
+        println!("{name:<24} {:>10.2?}", total / ITERATIONS);
+    }
+}
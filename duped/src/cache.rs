@@ -0,0 +1,144 @@
+//! Controls how the reads done while hashing interact with the page cache.
+//!
+//! A search reads every candidate file at least partially, and by default the kernel keeps what was read in the page
+//! cache, evicting data that other processes on the host actually need (e.g. the working set of a database).
+
+use std::{fs::File, io};
+
+/// How the reads done while hashing interact with the page cache.
+///
+/// Only Linux supports [`CacheMode::DropBehind`] and [`CacheMode::Direct`], other platforms always use
+/// [`CacheMode::Cached`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Reads go through the page cache, and the hashed content stays cached until the kernel needs the memory.
+    #[default]
+    Cached,
+    /// Reads go through the page cache, but the kernel is told that files are read sequentially, and that what was
+    /// read can be dropped from the cache right after it is hashed (with `posix_fadvise`).
+    ///
+    /// Note that this also drops the pages of hashed files that were cached before the search, while pages that weren't
+    /// written back to disk yet stay cached.
+    DropBehind,
+    /// Reads bypass the page cache entirely (with `O_DIRECT`), using aligned buffers.
+    ///
+    /// Files on file systems that don't support direct reads (e.g. tmpfs) are read like with
+    /// [`CacheMode::DropBehind`], and so are all files when they are read with io_uring, or memory mapped.
+    Direct,
+}
+
+/// Tell the kernel `file` is going to be read sequentially, so that it reads ahead more aggressively.
+pub(crate) fn advise_sequential(file: &File) {
+    #[cfg(target_os = "linux")]
+    advise(file, 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+    #[cfg(not(target_os = "linux"))]
+    let _ = file;
+}
+
+/// Tell the kernel the given region of `file` won't be read again, so that it can be dropped from the page cache.
+pub(crate) fn drop_from_cache(file: &File, offset: u64, len: u64) {
+    #[cfg(target_os = "linux")]
+    advise(file, offset, len, libc::POSIX_FADV_DONTNEED);
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset, len);
+}
+
+#[cfg(target_os = "linux")]
+fn advise(file: &File, offset: u64, len: u64, advice: libc::c_int) {
+    use std::os::fd::AsRawFd;
+
+    // advice is best effort, so errors are ignored
+    // SAFETY: the file descriptor is valid for the lifetime of `file`.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice);
+    }
+}
+
+/// The alignment of the buffers, offsets, and lengths of direct reads.
+#[cfg(target_os = "linux")]
+const DIRECT_ALIGNMENT: usize = 4096;
+
+/// The maximum size of a direct read. Direct reads bypass the kernel's read ahead, so they need to be large.
+#[cfg(target_os = "linux")]
+const DIRECT_READ_SIZE: usize = 1024 * 1024;
+
+/// Open `path` for direct reads, or return `None` if its file system doesn't support them.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_direct(path: &std::path::Path) -> io::Result<Option<File>> {
+    match crate::hasher::open_regular_file_with(path, libc::O_DIRECT) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read `len` bytes at `offset` from a file opened with [`open_direct`], and pass them to `feed` in order.
+///
+/// Direct reads must be aligned, so the region is extended to the closest aligned boundaries, and the extra bytes are
+/// not passed to `feed`.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_direct(
+    file: &File,
+    offset: u64,
+    len: u64,
+    mut feed: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let align = DIRECT_ALIGNMENT as u64;
+    let end = offset + len;
+    let mut position = offset / align * align;
+    let size = ((end - position).next_multiple_of(align) as usize).min(DIRECT_READ_SIZE);
+    let mut storage = vec![0; size + DIRECT_ALIGNMENT];
+    let start = storage.as_ptr().align_offset(DIRECT_ALIGNMENT);
+    let buffer = &mut storage[start..start + size];
+
+    while position < end {
+        let to_read = ((end - position).next_multiple_of(align) as usize).min(size);
+        let read = match file.read_at(&mut buffer[..to_read], position) {
+            Ok(read) => read as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // only the end of the file can be unaligned, so a short read must reach the end of the region
+        if read == 0 || (read % align != 0 && position + read < end) {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let skip = offset.saturating_sub(position) as usize;
+        let valid = read.min(end - position) as usize;
+        feed(&buffer[skip..valid]);
+        position += read;
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn direct_reads_are_unaligned_regions() {
+        // `/tmp` is often a tmpfs, which doesn't support direct reads, so the file is created next to the test binary,
+        // under the target directory
+        let exe = std::env::current_exe().unwrap();
+        let dir = tempfile::tempdir_in(exe.parent().unwrap()).unwrap();
+        let path = dir.path().join("a");
+        let data: Vec<u8> = (0..3 * DIRECT_READ_SIZE + 123).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let Some(file) = open_direct(&path).unwrap() else {
+            return;
+        };
+
+        for (offset, len) in [(0, data.len()), (1, 10), (4095, 2), (100, 2 * DIRECT_READ_SIZE + 5)]
+        {
+            let mut read = vec![];
+            read_direct(&file, offset as u64, len as u64, |data| read.extend_from_slice(data))
+                .unwrap();
+            assert_eq!(read, &data[offset..offset + len]);
+        }
+        let end = data.len() as u64;
+        assert!(read_direct(&file, end - 10, 20, |_| {}).is_err());
+    }
+}
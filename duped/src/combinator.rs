@@ -0,0 +1,247 @@
+//! Combinators that can be used to build complex [`DeduperFileFilter`]s out of simpler ones.
+//!
+//! See also: [`DeduperFileFilter::and`], [`DeduperFileFilter::or`], and [`DeduperFileFilter::not`].
+
+use crate::traits::{DeduperFileFilter, FileAction, FilterAction};
+
+use std::{fs, path::Path};
+
+/// A filter that includes a file only if both of its filters include it.
+///
+/// The second filter is not called if the first one excludes the file or stops the deduper. A directory is skipped if
+/// any of the two filters skips it.
+///
+/// Created by [`DeduperFileFilter::and`].
+#[derive(Debug)]
+pub struct And<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> And<A, B> {
+    /// Create a new instance.
+    pub fn new(a: A, b: B) -> Self {
+        Self { a, b }
+    }
+}
+
+impl<A: DeduperFileFilter, B: DeduperFileFilter> DeduperFileFilter for And<A, B> {
+    fn root_entered(&mut self, root: &Path) {
+        self.a.root_entered(root);
+        self.b.root_entered(root);
+    }
+
+    fn handle_file(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.a.handle_file(path, metadata) {
+            FilterAction::Continue(FileAction::Include) => self.b.handle_file(path, metadata),
+            action => action,
+        }
+    }
+
+    fn handle_dir(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.a.handle_dir(path, metadata) {
+            FilterAction::Continue(FileAction::Include) => self.b.handle_dir(path, metadata),
+            action => action,
+        }
+    }
+}
+
+/// A filter that includes a file if any of its filters include it.
+///
+/// The second filter is not called if the first one includes the file or stops the deduper. A directory is skipped only
+/// if both filters skip it.
+///
+/// Created by [`DeduperFileFilter::or`].
+#[derive(Debug)]
+pub struct Or<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> Or<A, B> {
+    /// Create a new instance.
+    pub fn new(a: A, b: B) -> Self {
+        Self { a, b }
+    }
+}
+
+impl<A: DeduperFileFilter, B: DeduperFileFilter> DeduperFileFilter for Or<A, B> {
+    fn root_entered(&mut self, root: &Path) {
+        self.a.root_entered(root);
+        self.b.root_entered(root);
+    }
+
+    fn handle_file(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.a.handle_file(path, metadata) {
+            FilterAction::Continue(FileAction::Exclude) => self.b.handle_file(path, metadata),
+            action => action,
+        }
+    }
+
+    fn handle_dir(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.a.handle_dir(path, metadata) {
+            FilterAction::Continue(FileAction::Exclude) => self.b.handle_dir(path, metadata),
+            action => action,
+        }
+    }
+}
+
+/// A filter that includes the files its inner filter excludes, and vice versa.
+///
+/// If the inner filter stops the deduper, so does this filter. Directories are never skipped, since the inner filter
+/// skipping a directory means all the files underneath it should be included.
+///
+/// Created by [`DeduperFileFilter::not`].
+#[derive(Debug)]
+pub struct Not<F> {
+    inner: F,
+}
+
+impl<F> Not<F> {
+    /// Create a new instance.
+    pub fn new(inner: F) -> Self {
+        Self { inner }
+    }
+}
+
+impl<F: DeduperFileFilter> DeduperFileFilter for Not<F> {
+    fn root_entered(&mut self, root: &Path) {
+        self.inner.root_entered(root);
+    }
+
+    fn handle_file(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.inner.handle_file(path, metadata) {
+            FilterAction::Continue(FileAction::Include) => {
+                FilterAction::Continue(FileAction::Exclude)
+            }
+            FilterAction::Continue(FileAction::Exclude) => {
+                FilterAction::Continue(FileAction::Include)
+            }
+            FilterAction::Break(()) => FilterAction::Break(()),
+        }
+    }
+
+    fn handle_dir(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        match self.inner.handle_dir(path, metadata) {
+            FilterAction::Break(()) => FilterAction::Break(()),
+            FilterAction::Continue(_) => FilterAction::Continue(FileAction::Include),
+        }
+    }
+}
+
+/// Any closure with the same signature as [`DeduperFileFilter::handle_file`] is a filter.
+impl<F> DeduperFileFilter for F
+where
+    F: FnMut(&Path, &fs::Metadata) -> FilterAction,
+{
+    fn handle_file(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+        self(path, metadata)
+    }
+}
+
+/// Implements [`DeduperFileFilter`] for tuples of filters. A tuple behaves like nested [`And`]s: a file is included
+/// (or a directory walked) only if all filters include it, and filters are called in order until one of them excludes
+/// the entry or stops the deduper.
+macro_rules! tuple_filter {
+    ($($name:ident),+) => {
+        impl<$($name: DeduperFileFilter),+> DeduperFileFilter for ($($name,)+) {
+            fn root_entered(&mut self, root: &Path) {
+                #[allow(non_snake_case)]
+                let ($($name,)+) = self;
+                $($name.root_entered(root);)+
+            }
+
+            fn handle_file(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+                #[allow(non_snake_case)]
+                let ($($name,)+) = self;
+                $(
+                    match $name.handle_file(path, metadata) {
+                        FilterAction::Continue(FileAction::Include) => {}
+                        action => return action,
+                    }
+                )+
+                FilterAction::Continue(FileAction::Include)
+            }
+
+            fn handle_dir(&mut self, path: &Path, metadata: &fs::Metadata) -> FilterAction {
+                #[allow(non_snake_case)]
+                let ($($name,)+) = self;
+                $(
+                    match $name.handle_dir(path, metadata) {
+                        FilterAction::Continue(FileAction::Include) => {}
+                        action => return action,
+                    }
+                )+
+                FilterAction::Continue(FileAction::Include)
+            }
+        }
+    };
+}
+
+tuple_filter!(A);
+tuple_filter!(A, B);
+tuple_filter!(A, B, C);
+tuple_filter!(A, B, C, D);
+tuple_filter!(A, B, C, D, E);
+tuple_filter!(A, B, C, D, E, F);
+tuple_filter!(A, B, C, D, E, F, G);
+tuple_filter!(A, B, C, D, E, F, G, H);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    fn include(_: &Path, _: &fs::Metadata) -> FilterAction {
+        FilterAction::Continue(FileAction::Include)
+    }
+
+    fn exclude(_: &Path, _: &fs::Metadata) -> FilterAction {
+        FilterAction::Continue(FileAction::Exclude)
+    }
+
+    fn stop(_: &Path, _: &fs::Metadata) -> FilterAction {
+        FilterAction::Break(())
+    }
+
+    fn run(mut filter: impl DeduperFileFilter) -> FilterAction {
+        let metadata = fs::metadata(".").unwrap();
+        filter.handle_file(Path::new("a"), &metadata)
+    }
+
+    fn is_include(action: FilterAction) -> bool {
+        matches!(action, FilterAction::Continue(FileAction::Include))
+    }
+
+    fn is_exclude(action: FilterAction) -> bool {
+        matches!(action, FilterAction::Continue(FileAction::Exclude))
+    }
+
+    #[test]
+    fn combinators() {
+        assert!(is_include(run(include.and(include))));
+        assert!(is_exclude(run(include.and(exclude))));
+        assert!(is_include(run(exclude.or(include))));
+        assert!(is_exclude(run(exclude.or(exclude))));
+        assert!(is_include(run(exclude.not())));
+        assert!(is_exclude(run((include, include.not(), include))));
+        assert!(is_include(run((include, exclude.or(include)))));
+    }
+
+    #[test]
+    fn break_short_circuits() {
+        let called = Cell::new(false);
+        let spy = |_: &Path, _: &fs::Metadata| {
+            called.set(true);
+            FilterAction::Continue(FileAction::Include)
+        };
+        assert!(run(stop.and(spy)).is_break());
+        assert!(run(stop.or(spy)).is_break());
+        assert!(run(stop.not()).is_break());
+        assert!(run((stop, spy)).is_break());
+        assert!(!called.get());
+        assert!(run(include.and(stop)).is_break());
+        assert!(run(exclude.or(stop)).is_break());
+    }
+}
@@ -1,32 +1,64 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt, io,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 use blake3::Hash;
 
+use crate::{file::CompactPath, stats::ScanStats, FileKind};
+
 /// Metadata about a file that has been processed by [`crate::Deduper`].
 #[derive(Clone, Debug)]
 pub struct FileEntry {
-    path: PathBuf,
+    path: CompactPath,
     size: u64,
+    is_sparse: bool,
+    root: u32,
+    modified: Option<SystemTime>,
 }
 
 impl FileEntry {
     /// Create a new instance.
-    pub(crate) fn new(path: PathBuf, size: u64) -> Self {
-        Self { path, size }
+    pub(crate) fn new(
+        path: CompactPath,
+        size: u64,
+        is_sparse: bool,
+        root: u32,
+        modified: Option<SystemTime>,
+    ) -> Self {
+        Self { path, size, is_sparse, root, modified }
     }
 
     /// Get the path of the file.
-    pub fn path(&self) -> &Path {
-        &self.path
+    ///
+    /// Paths are stored compactly, as the name of the file and a directory shared with the other files of the same
+    /// directory, so the path is built on each call.
+    pub fn path(&self) -> PathBuf {
+        self.path.to_path_buf()
     }
 
     /// Get the size of the file.
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    /// Return `true` if the file is sparse, i.e. if it takes less space on disk than its size, because some of its
+    /// regions were never written to.
+    pub fn is_sparse(&self) -> bool {
+        self.is_sparse
+    }
+
+    /// Get the index of the root the file was found in, see [`crate::Deduper::roots`].
+    pub fn root_index(&self) -> usize {
+        self.root as usize
+    }
+
+    /// Get the last modification time of the file, as it was when the file was found, if the platform supports it.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
 }
 
 /// Files that share the same hash.
@@ -49,6 +81,31 @@ impl FileEntries {
         self.files.len() > 1
     }
 
+    /// Sort the entries by path, so that they are listed in the same order from one search to the next.
+    fn sort(&mut self) {
+        self.files.sort_by_cached_key(|e| e.path());
+    }
+
+    /// The number of files that share the same hash.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Return `true` if there are no files, which never happens for the entries of a [`DeduperResult`].
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Return `true` if the files were found in more than one root.
+    pub fn spans_roots(&self) -> bool {
+        self.files.windows(2).any(|files| files[0].root != files[1].root)
+    }
+
+    /// How many bytes could be saved by keeping only one of the files.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.file_size() * (self.files.len().saturating_sub(1) as u64)
+    }
+
     /// The file size shared by all entries.
     ///
     /// Since [`FileEntries`] stores all files that were hashed to the same value, each [`FileEntry`] is going to have the same size. This value is returned from this function.
@@ -56,10 +113,96 @@ impl FileEntries {
         self.files.first().map(|e| e.size()).unwrap_or(0)
     }
 
-    /// Return all file paths stored by this instance.
-    pub fn iter(&self) -> impl Iterator<Item = &Path> {
+    /// Return all file paths stored by this instance, sorted.
+    pub fn iter(&self) -> impl Iterator<Item = PathBuf> + '_ {
         self.files.iter().map(|e| e.path())
     }
+
+    /// Return all file entries stored by this instance, sorted by path.
+    pub fn entries(&self) -> impl Iterator<Item = &FileEntry> {
+        self.files.iter()
+    }
+}
+
+/// Why [`crate::Deduper`] didn't process a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipReason {
+    /// The entry is not a regular file.
+    NotRegularFile(FileKind),
+    /// An I/O error occurred while reading the entry.
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotRegularFile(kind) => write!(f, "not a regular file ({kind})"),
+            Self::Io(kind) => write!(f, "i/o error ({kind})"),
+        }
+    }
+}
+
+/// A file that was skipped by [`crate::Deduper`].
+#[derive(Clone, Debug)]
+pub struct SkippedEntry {
+    path: PathBuf,
+    reason: SkipReason,
+}
+
+impl SkippedEntry {
+    /// Create a new instance.
+    pub(crate) fn new(path: PathBuf, reason: SkipReason) -> Self {
+        Self { path, reason }
+    }
+
+    /// Get the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the reason why the file was skipped.
+    pub fn reason(&self) -> SkipReason {
+        self.reason
+    }
+}
+
+/// The order in which [`DeduperResult::sorted_duplicates`] lists groups of duplicates.
+///
+/// Groups that are equal according to the order are sorted by the path of their first file.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DuplicateOrder {
+    /// The groups that waste the most space first, i.e. by file size times the number of copies.
+    #[default]
+    WastedBytes,
+    /// The largest files first.
+    Size,
+    /// By the path of the first file of each group.
+    FirstPath,
+    /// The groups with the most files first.
+    Count,
+}
+
+/// Which groups of duplicates a [`DeduperResult`] lists, depending on the roots their files were found in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RootFilter {
+    /// All groups.
+    #[default]
+    All,
+    /// Only the groups whose files were found in at least two different roots.
+    AcrossRoots,
+    /// Only the groups whose files were all found in the same root.
+    WithinRoot,
+}
+
+impl RootFilter {
+    /// Return `true` if a group whose files were found in more than one root (or not) is listed.
+    pub(crate) fn includes(&self, spans_roots: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::AcrossRoots => spans_roots,
+            Self::WithinRoot => !spans_roots,
+        }
+    }
 }
 
 /// A collection of duplicates.
@@ -69,6 +212,18 @@ pub struct DeduperResult {
     hashes: HashMap<Hash, FileEntries>,
     /// Whether the user interrupted the find operations.
     is_partial: bool,
+    /// Files that were not processed.
+    skipped: Vec<SkippedEntry>,
+    /// Where the search spent its time.
+    stats: ScanStats,
+    /// Which groups of duplicates are listed.
+    root_filter: RootFilter,
+    /// The index of the first reference root, if any, see [`crate::DeduperBuilder::reference_roots`].
+    first_reference_root: Option<usize>,
+    /// Whether all files were hashed entirely, see [`crate::DeduperBuilder::hash_all_files`].
+    fully_hashed: bool,
+    /// The directories in which the filter or the walk options excluded a file or a subdirectory.
+    filtered_dirs: HashSet<PathBuf>,
 }
 
 impl DeduperResult {
@@ -82,6 +237,43 @@ impl DeduperResult {
         self.hashes.entry(hash).or_insert_with(|| FileEntries::new(vec![])).push(file)
     }
 
+    /// Sort the entries of each group by path.
+    pub(crate) fn sort_entries(&mut self) {
+        for entries in self.hashes.values_mut() {
+            entries.sort();
+        }
+    }
+
+    /// Record files that were not processed.
+    pub(crate) fn add_skipped(&mut self, skipped: impl IntoIterator<Item = SkippedEntry>) {
+        self.skipped.extend(skipped);
+    }
+
+    /// Make this instance return true from `is_fully_hashed`.
+    pub(crate) fn set_fully_hashed(&mut self) {
+        self.fully_hashed = true;
+    }
+
+    /// Record the directories in which the filter or the walk options excluded a file or a subdirectory.
+    pub(crate) fn set_filtered_dirs(&mut self, dirs: HashSet<PathBuf>) {
+        self.filtered_dirs = dirs;
+    }
+
+    /// Get the directories in which the filter or the walk options excluded a file or a subdirectory.
+    pub(crate) fn filtered_dirs(&self) -> &HashSet<PathBuf> {
+        &self.filtered_dirs
+    }
+
+    /// Record which roots are reference roots.
+    pub(crate) fn set_first_reference_root(&mut self, first: Option<usize>) {
+        self.first_reference_root = first;
+    }
+
+    /// Get the statistics of the search, to fill them in.
+    pub(crate) fn stats_mut(&mut self) -> &mut ScanStats {
+        &mut self.stats
+    }
+
     /// Get the collection of hashes and files that were gathered during [`crate::Deduper::find`].
     ///
     /// Each entry consists of a hash, and all the files that share the same hash. If an entry has only one path, that
@@ -90,9 +282,74 @@ impl DeduperResult {
         &self.hashes
     }
 
+    /// Only list the groups of duplicates that pass `filter` from now on, in [`Self::duplicates`] and in the methods
+    /// built on it (the reports, and [`Self::duplicate_trees`]). [`Self::hashes`] still returns all files.
+    pub fn set_root_filter(&mut self, filter: RootFilter) {
+        self.root_filter = filter;
+    }
+
+    /// Get the filter set with [`Self::set_root_filter`].
+    pub fn root_filter(&self) -> RootFilter {
+        self.root_filter
+    }
+
+    /// Return `true` if `entry` was found in one of the roots added with [`crate::DeduperBuilder::reference_roots`].
+    pub fn is_reference(&self, entry: &FileEntry) -> bool {
+        self.first_reference_root.is_some_and(|first| entry.root_index() >= first)
+    }
+
+    /// Return the files of the roots that aren't reference roots, which have no copy in any of the reference roots (see
+    /// [`crate::DeduperBuilder::reference_roots`]), sorted by path.
+    ///
+    /// Files that couldn't be read are not listed, see [`Self::skipped`].
+    pub fn missing_from_reference(&self) -> Vec<&FileEntry> {
+        let mut missing: Vec<_> = self
+            .hashes
+            .values()
+            .filter(|entries| !entries.files.iter().any(|entry| self.is_reference(entry)))
+            .flat_map(|entries| &entries.files)
+            .collect();
+        missing.sort_by_cached_key(|entry| entry.path());
+        missing
+    }
+
     /// Return an interator of all duplicated file entries.
+    ///
+    /// The groups are listed in no particular order, which changes from one search to the next. See
+    /// [`Self::sorted_duplicates`] for a stable order.
     pub fn duplicates(&self) -> impl Iterator<Item = (&Hash, &FileEntries)> {
-        self.hashes.iter().filter(|(_, entries)| entries.has_duplicates())
+        self.hashes.iter().filter(|(_, entries)| {
+            entries.has_duplicates() && self.root_filter.includes(entries.spans_roots())
+        })
+    }
+
+    /// Return all duplicated file entries, sorted by `order`.
+    ///
+    /// The order only depends on the files that were found, so two searches of the same files list them in the same
+    /// order.
+    pub fn sorted_duplicates(&self, order: DuplicateOrder) -> Vec<(&Hash, &FileEntries)> {
+        let mut duplicates: Vec<_> = self
+            .duplicates()
+            .map(|(hash, entries)| {
+                let first = entries.files.first().map(|e| e.path()).unwrap_or_default();
+                (first, hash, entries)
+            })
+            .collect();
+        duplicates.sort_by(|(first1, hash1, entries1), (first2, hash2, entries2)| {
+            // larger values first
+            let key = |entries: &FileEntries| match order {
+                DuplicateOrder::WastedBytes => entries.wasted_bytes(),
+                DuplicateOrder::Size => entries.file_size(),
+                DuplicateOrder::FirstPath => 0,
+                DuplicateOrder::Count => entries.len() as u64,
+            };
+            key(entries2)
+                .cmp(&key(entries1))
+                .then_with(|| first1.cmp(first2))
+                .then_with(|| hash1.as_bytes().cmp(hash2.as_bytes()))
+        });
+
+        duplicates.into_iter().map(|(_, hash, entries)| (hash, entries)).collect()
     }
 
     /// Return `true` if the find operation was stopped prematurely and the results are only partial.
@@ -102,4 +359,23 @@ impl DeduperResult {
     pub fn is_partial(&self) -> bool {
         self.is_partial
     }
+
+    /// Return `true` if every hash of [`Self::hashes`] is the hash of the entire contents of its files, see
+    /// [`crate::DeduperBuilder::hash_all_files`].
+    pub fn is_fully_hashed(&self) -> bool {
+        self.fully_hashed
+    }
+
+    /// Return the files that were not processed, and why.
+    ///
+    /// Only regular files are hashed, so this includes symbolic links, named pipes, sockets and devices, along with
+    /// files that couldn't be read.
+    pub fn skipped(&self) -> &[SkippedEntry] {
+        &self.skipped
+    }
+
+    /// Return statistics about where the search spent its time, and how much it had to read.
+    pub fn stats(&self) -> &ScanStats {
+        &self.stats
+    }
 }
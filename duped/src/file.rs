@@ -1,35 +1,298 @@
-use crate::FileEntry;
+use crate::{sparse, spill, FileEntry};
 
 use std::{
-    fs::Metadata,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{FileType, Metadata},
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-/// A path and its metadata.
+/// The type of a file system entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum FileKind {
+    /// A regular file, the only kind of file [`crate::Deduper`] hashes.
+    Regular,
+    /// A directory.
+    Directory,
+    /// A symbolic link.
+    Symlink,
+    /// A named pipe. Opening one for reading blocks until a writer shows up.
+    Fifo,
+    /// A unix domain socket.
+    Socket,
+    /// A block device, such as a disk.
+    BlockDevice,
+    /// A character device, such as a terminal or `/dev/zero`.
+    CharDevice,
+    /// Any other kind of entry.
+    Unknown,
+}
+
+impl FileKind {
+    /// Classify an entry based on its [`FileType`].
+    pub fn from_file_type(file_type: FileType) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            if file_type.is_fifo() {
+                return Self::Fifo;
+            } else if file_type.is_socket() {
+                return Self::Socket;
+            } else if file_type.is_block_device() {
+                return Self::BlockDevice;
+            } else if file_type.is_char_device() {
+                return Self::CharDevice;
+            }
+        }
+
+        if file_type.is_file() {
+            Self::Regular
+        } else if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Regular => "regular file",
+            Self::Directory => "directory",
+            Self::Symlink => "symbolic link",
+            Self::Fifo => "named pipe",
+            Self::Socket => "socket",
+            Self::BlockDevice => "block device",
+            Self::CharDevice => "character device",
+            Self::Unknown => "unknown file type",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// A directory, whose path is shared by all the entries it contains.
+///
+/// Storing the whole path of every file wastes a lot of memory on deep trees, so files only store their name and their
+/// parent directory, which only stores its own name and parent, and so on.
+#[derive(Debug)]
+pub(crate) struct DirNode {
+    parent: Option<Arc<DirNode>>,
+    /// The name of the directory, or its whole path if it has no parent.
+    name: Box<OsStr>,
+}
+
+impl DirNode {
+    /// Create a directory that has no parent, e.g. a root.
+    pub(crate) fn root(path: &Path) -> Arc<Self> {
+        Arc::new(Self { parent: None, name: path.as_os_str().into() })
+    }
+
+    /// Create a subdirectory of `self`.
+    pub(crate) fn child(self: &Arc<Self>, name: &OsStr) -> Arc<Self> {
+        Arc::new(Self { parent: Some(Arc::clone(self)), name: name.into() })
+    }
+
+    /// Append the path of the directory to `path`.
+    fn push_to(&self, path: &mut PathBuf) {
+        let mut ancestors = vec![&self.name];
+        let mut parent = self.parent.as_deref();
+        while let Some(dir) = parent {
+            ancestors.push(&dir.name);
+            parent = dir.parent.as_deref();
+        }
+        for name in ancestors.into_iter().rev() {
+            path.push(&**name);
+        }
+    }
+}
+
+/// The path of a file, stored as its name and its (shared) parent directory.
+#[derive(Clone)]
+pub(crate) struct CompactPath {
+    parent: Option<Arc<DirNode>>,
+    /// The name of the file, or its whole path if it has no parent.
+    name: Box<OsStr>,
+}
+
+impl CompactPath {
+    /// Create the path of the file `path`, which is in the `parent` directory (if any).
+    pub(crate) fn new(parent: Option<Arc<DirNode>>, path: PathBuf) -> Self {
+        match (parent, path.file_name()) {
+            (Some(parent), Some(name)) => Self { parent: Some(parent), name: name.into() },
+            _ => path.into(),
+        }
+    }
+
+    /// Build the whole path.
+    pub(crate) fn to_path_buf(&self) -> PathBuf {
+        let mut path = PathBuf::new();
+        if let Some(parent) = &self.parent {
+            parent.push_to(&mut path);
+        }
+        path.push(&*self.name);
+
+        path
+    }
+}
+
+impl From<PathBuf> for CompactPath {
+    fn from(path: PathBuf) -> Self {
+        Self { parent: None, name: path.into_os_string().into_boxed_os_str() }
+    }
+}
+
+impl fmt::Debug for CompactPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_path_buf().fmt(f)
+    }
+}
+
+/// Interns the parent directories of decoded paths, so that files of the same directory share it again.
+#[derive(Default)]
+pub(crate) struct DirInterner {
+    dirs: HashMap<PathBuf, Arc<DirNode>>,
+}
+
+impl DirInterner {
+    /// Return the path of the file `path`, with an interned parent directory.
+    pub(crate) fn intern(&mut self, path: PathBuf) -> CompactPath {
+        let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+            return path.into();
+        };
+        let parent = match self.dirs.get(dir) {
+            Some(parent) => Arc::clone(parent),
+            None => {
+                let parent = DirNode::root(dir);
+                self.dirs.insert(dir.to_owned(), Arc::clone(&parent));
+                parent
+            }
+        };
+
+        CompactPath::new(Some(parent), path)
+    }
+}
+
+/// A path and the little of its metadata that is needed to hash it.
+///
+/// Millions of these are kept in memory during a search, so the whole [`Metadata`] is not kept around.
+#[derive(Debug)]
 pub struct FilePath {
-    path: PathBuf,
-    metadata: Metadata,
+    path: CompactPath,
+    len: u64,
+    /// The device the file is on (always `0` on platforms other than unix).
+    device: u64,
+    /// The inode number of the file (always `0` on platforms other than unix).
+    inode: u64,
+    is_sparse: bool,
+    /// The index of the root the file was found in.
+    root: u32,
+    /// When the file was last modified, if the platform supports it.
+    modified: Option<SystemTime>,
 }
 
 impl FilePath {
-    /// Creates a new instance by reading `path`'s metadata.
-    pub fn try_new(path: PathBuf) -> std::io::Result<Self> {
-        let metadata = path.metadata()?;
-        Ok(Self { path, metadata })
+    /// Creates a new instance from a path, and its metadata.
+    pub fn new(path: CompactPath, metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        let (device, inode) = {
+            use std::os::unix::fs::MetadataExt;
+
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (device, inode) = (0, 0);
+
+        Self {
+            path,
+            len: metadata.len(),
+            device,
+            inode,
+            is_sparse: sparse::is_sparse(metadata),
+            root: 0,
+            modified: metadata.modified().ok(),
+        }
+    }
+
+    /// Set the index of the root the file was found in.
+    pub(crate) fn with_root(mut self, root: usize) -> Self {
+        self.root = root as u32;
+
+        self
+    }
+
+    /// Gets the index of the root the file was found in.
+    pub(crate) fn root_index(&self) -> usize {
+        self.root as usize
     }
 
     /// Gets the path.
-    pub fn path(&self) -> &Path {
-        &self.path
+    pub fn path(&self) -> PathBuf {
+        self.path.to_path_buf()
+    }
+
+    /// Gets the size of the file.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Gets the device the file is on.
+    pub(crate) fn device(&self) -> u64 {
+        self.device
     }
 
-    /// Gets the metadata of a particular file.
-    pub fn metadata(&self) -> &Metadata {
-        &self.metadata
+    /// Gets the inode number of the file.
+    pub(crate) fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    /// Returns whether the file is sparse, i.e. whether it takes less space on disk than its size.
+    pub fn is_sparse(&self) -> bool {
+        self.is_sparse
     }
 
     /// Converts this instance into a [`FileEntry`].
     pub fn to_file_entry(&self) -> FileEntry {
-        FileEntry::new(self.path.clone(), self.metadata.len())
+        FileEntry::new(self.path.clone(), self.len, self.is_sparse, self.root, self.modified)
+    }
+
+    /// Append the encoded path and metadata to `out`, see [`crate::spill`].
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        spill::put_bytes(out, self.path().as_os_str().as_encoded_bytes());
+        // times before the epoch are rare enough to be forgotten
+        let (secs, nanos) = match self.modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()) {
+            Some(modified) => (modified.as_secs(), modified.subsec_nanos().into()),
+            None => (u64::MAX, 0),
+        };
+        let root = self.root.into();
+        for value in [self.len, self.device, self.inode, self.is_sparse as u64, root, secs, nanos] {
+            spill::put_u64(out, value);
+        }
+    }
+
+    /// Decode an instance encoded with [`Self::encode`].
+    pub(crate) fn decode(input: &mut &[u8], dirs: &mut DirInterner) -> Option<Self> {
+        // SAFETY: the bytes were encoded by `Self::encode`, in the same process.
+        let path =
+            unsafe { OsString::from_encoded_bytes_unchecked(spill::take_bytes(input)?.to_vec()) };
+
+        Some(Self {
+            path: dirs.intern(path.into()),
+            len: spill::take_u64(input)?,
+            device: spill::take_u64(input)?,
+            inode: spill::take_u64(input)?,
+            is_sparse: spill::take_u64(input)? != 0,
+            root: spill::take_u64(input)?.try_into().ok()?,
+            modified: match (spill::take_u64(input)?, spill::take_u64(input)?) {
+                (u64::MAX, _) => None,
+                (secs, nanos) => UNIX_EPOCH.checked_add(Duration::new(secs, nanos as u32)),
+            },
+        })
     }
 }
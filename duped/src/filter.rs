@@ -0,0 +1,237 @@
+//! [`DeduperFileFilter`] implementations that select files based on their path.
+//!
+//! Paths are matched relative to the root that is currently being walked, so a pattern such as `src/*.rs` behaves the
+//! same regardless of where the root lives on disk.
+
+use crate::traits::{DeduperFileFilter, FileAction, FilterAction};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Strip `root` from `path`, or return `path` as is if it is not a descendant of `root`.
+fn relative_to<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// Return the action to take based on whether the path matched an include pattern and an exclude pattern.
+fn action(included: bool, excluded: bool) -> FilterAction {
+    if included && !excluded {
+        FilterAction::Continue(FileAction::Include)
+    } else {
+        FilterAction::Continue(FileAction::Exclude)
+    }
+}
+
+/// A [`DeduperFileFilter`] that includes or excludes files based on glob patterns.
+///
+/// Patterns follow `.gitignore` conventions:
+/// * a pattern without a `/` matches the file name at any depth (`*.tmp`);
+/// * a pattern with a `/` is matched against the whole path, relative to the root (`src/*.rs`);
+/// * a leading `/` anchors the pattern to the root (`/build`);
+/// * a trailing `/` matches everything inside a directory with that name (`node_modules/`).
+///
+/// `*` does not match path separators, use `**` to match any number of directories.
+///
+/// If there is at least one include pattern, only files that match one of them are processed. Files that match any
+/// exclude pattern are never processed.
+#[derive(Debug)]
+pub struct GlobFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// Directories that match any of these patterns are not walked at all.
+    exclude_dirs: GlobSet,
+    /// The root that is currently being walked.
+    root: PathBuf,
+}
+
+impl GlobFilter {
+    /// Create a new filter from a list of include and a list of exclude patterns.
+    ///
+    /// Directories that match exclude patterns with a trailing `/` are skipped entirely.
+    pub fn new<I, E>(include: I, exclude: E) -> Result<Self, globset::Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+        E: IntoIterator,
+        E::Item: AsRef<str>,
+    {
+        let include: Vec<_> = include.into_iter().map(|p| Self::expand(p.as_ref())).collect();
+        let exclude: Vec<_> = exclude.into_iter().map(|p| Self::expand(p.as_ref())).collect();
+
+        let include = Self::build_set(include.iter().flat_map(Self::file_globs))?;
+        let include = if include.is_empty() { None } else { Some(include) };
+        let exclude_dirs = Self::build_set(
+            exclude.iter().filter(|(_, is_dir)| *is_dir).flat_map(|(globs, _)| globs.clone()),
+        )?;
+        let exclude = Self::build_set(exclude.iter().flat_map(Self::file_globs))?;
+
+        Ok(Self { include, exclude, exclude_dirs, root: PathBuf::new() })
+    }
+
+    fn build_set(globs: impl IntoIterator<Item = String>) -> Result<GlobSet, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(GlobBuilder::new(&glob).literal_separator(true).build()?);
+        }
+        builder.build()
+    }
+
+    /// Translate a `.gitignore`-like pattern into one or more globs, and return whether the pattern only matches
+    /// directories.
+    fn expand(pattern: &str) -> (Vec<String>, bool) {
+        let (pattern, is_dir) = match pattern.strip_suffix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        let anchored = pattern.starts_with('/') || pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+
+        let globs = if anchored || pattern.starts_with("**/") {
+            vec![pattern.to_owned()]
+        } else {
+            vec![format!("**/{pattern}"), pattern.to_owned()]
+        };
+        (globs, is_dir)
+    }
+
+    /// Return the globs that match the files of an expanded pattern.
+    fn file_globs((globs, is_dir): &(Vec<String>, bool)) -> Vec<String> {
+        if *is_dir {
+            globs.iter().map(|glob| format!("{glob}/**")).collect()
+        } else {
+            globs.clone()
+        }
+    }
+}
+
+impl DeduperFileFilter for GlobFilter {
+    fn root_entered(&mut self, root: &Path) {
+        self.root = root.to_owned();
+    }
+
+    fn handle_file(&mut self, path: &Path, _: &fs::Metadata) -> FilterAction {
+        let path = relative_to(&self.root, path);
+        let included = self.include.as_ref().map(|set| set.is_match(path)).unwrap_or(true);
+
+        action(included, self.exclude.is_match(path))
+    }
+
+    fn handle_dir(&mut self, path: &Path, _: &fs::Metadata) -> FilterAction {
+        let path = relative_to(&self.root, path);
+
+        action(true, self.exclude_dirs.is_match(path))
+    }
+}
+
+/// A [`DeduperFileFilter`] that includes or excludes files based on regular expressions.
+///
+/// Expressions are matched against the path of the file relative to the root, and are not anchored, so `\.tmp$`
+/// matches all files ending in `.tmp`. Since an expression that matches a directory doesn't necessarily match the files
+/// inside it, directories are always walked.
+///
+/// If there is at least one include expression, only files that match one of them are processed. Files that match any
+/// exclude expression are never processed.
+#[derive(Debug)]
+pub struct RegexFilter {
+    include: Option<RegexSet>,
+    exclude: RegexSet,
+    /// The root that is currently being walked.
+    root: PathBuf,
+}
+
+impl RegexFilter {
+    /// Create a new filter from a list of include and a list of exclude expressions.
+    pub fn new<I, E>(include: I, exclude: E) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+        E: IntoIterator,
+        E::Item: AsRef<str>,
+    {
+        let include = RegexSet::new(include)?;
+        let include = if include.is_empty() { None } else { Some(include) };
+        let exclude = RegexSet::new(exclude)?;
+
+        Ok(Self { include, exclude, root: PathBuf::new() })
+    }
+}
+
+impl DeduperFileFilter for RegexFilter {
+    fn root_entered(&mut self, root: &Path) {
+        self.root = root.to_owned();
+    }
+
+    fn handle_file(&mut self, path: &Path, _: &fs::Metadata) -> FilterAction {
+        let path = relative_to(&self.root, path).to_string_lossy();
+        let included = self.include.as_ref().map(|set| set.is_match(&path)).unwrap_or(true);
+
+        action(included, self.exclude.is_match(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_included(filter: &mut impl DeduperFileFilter, path: &str) -> bool {
+        let metadata = fs::metadata(".").unwrap();
+        filter.root_entered(Path::new("/root"));
+        let path = Path::new("/root").join(path);
+        matches!(filter.handle_file(&path, &metadata), FilterAction::Continue(FileAction::Include))
+    }
+
+    #[test]
+    fn glob_exclude() {
+        let mut filter =
+            GlobFilter::new(&[] as &[&str], ["*.tmp", "node_modules/", "/build"]).unwrap();
+        assert!(!is_included(&mut filter, "a.tmp"));
+        assert!(!is_included(&mut filter, "a/b/c.tmp"));
+        assert!(!is_included(&mut filter, "node_modules/a"));
+        assert!(!is_included(&mut filter, "a/node_modules/b/c"));
+        assert!(!is_included(&mut filter, "build"));
+        assert!(is_included(&mut filter, "a/build"));
+        assert!(is_included(&mut filter, "a.tmp.rs"));
+        assert!(is_included(&mut filter, "node_modules"));
+    }
+
+    #[test]
+    fn glob_exclude_dirs() {
+        let mut filter = GlobFilter::new(&[] as &[&str], ["node_modules/", "*.tmp"]).unwrap();
+        let metadata = fs::metadata(".").unwrap();
+        filter.root_entered(Path::new("/root"));
+        let is_walked = |filter: &mut GlobFilter, path: &str| {
+            let path = Path::new("/root").join(path);
+            matches!(
+                filter.handle_dir(&path, &metadata),
+                FilterAction::Continue(FileAction::Include)
+            )
+        };
+        assert!(!is_walked(&mut filter, "node_modules"));
+        assert!(!is_walked(&mut filter, "a/node_modules"));
+        assert!(is_walked(&mut filter, "a/node_modules2"));
+        assert!(is_walked(&mut filter, "a.tmp"));
+    }
+
+    #[test]
+    fn glob_include() {
+        let mut filter = GlobFilter::new(["src/*.rs"], ["main.rs"]).unwrap();
+        assert!(is_included(&mut filter, "src/lib.rs"));
+        assert!(!is_included(&mut filter, "src/main.rs"));
+        assert!(!is_included(&mut filter, "src/a/lib.rs"));
+        assert!(!is_included(&mut filter, "lib.rs"));
+    }
+
+    #[test]
+    fn regex_filter() {
+        let mut filter = RegexFilter::new([r"\.(jpg|png)$"], [r"^thumbs/"]).unwrap();
+        assert!(is_included(&mut filter, "a/b.jpg"));
+        assert!(is_included(&mut filter, "b.png"));
+        assert!(!is_included(&mut filter, "thumbs/b.png"));
+        assert!(!is_included(&mut filter, "b.gif"));
+    }
+}
@@ -1,22 +1,303 @@
 //! Provides utilities to hash files in a progressive manner (i.e. in chunks, rather than entire files in one go).
 
-use crate::file::FilePath;
+use crate::{
+    cache::{self, CacheMode},
+    file::{DirInterner, FilePath},
+    sparse,
+    spill::{self, CandidateList, SpillConfig},
+    throttle::Throttle,
+};
 
-use std::io::{self, Read, Seek};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek},
+    path::Path,
+};
+
+/// The regions of a file that are hashed before the file is hashed sequentially.
+///
+/// Files of the same size often share the same header (e.g. disk images, or video exports), and only differ near the
+/// end. Hashing a few small blocks spread across the file is a cheap way of telling these files apart, before
+/// reading them in their entirety. Each probe takes one round:
+/// 1. a block at the start of the file;
+/// 2. a block at the end of the file;
+/// 3. a number of evenly spaced samples from the middle of the file.
+///
+/// Files that are not larger than twice the number of probed bytes are hashed sequentially straight away.
+#[derive(Clone, Debug)]
+pub struct ProbeSchedule {
+    /// How many bytes to hash from the start of the file.
+    head: u64,
+    /// How many bytes to hash from the end of the file.
+    tail: u64,
+    /// How many samples to hash from the middle of the file.
+    samples: u64,
+    /// The size of each sample.
+    sample_size: u64,
+}
+
+impl ProbeSchedule {
+    /// Create a schedule that hashes 4 KiBs from the start, 4 KiBs from the end, and 4 samples of 4 KiBs from the
+    /// middle of a file.
+    pub fn new() -> Self {
+        Self { head: 4 * 1024, tail: 4 * 1024, samples: 4, sample_size: 4 * 1024 }
+    }
+
+    /// Create a schedule that doesn't probe files, so that files are only hashed sequentially.
+    pub fn disabled() -> Self {
+        Self { head: 0, tail: 0, samples: 0, sample_size: 0 }
+    }
+
+    /// Recreate the instance with a new number of bytes to hash from the start of a file.
+    pub fn with_head(mut self, head: u64) -> Self {
+        self.head = head;
+
+        self
+    }
+
+    /// Recreate the instance with a new number of bytes to hash from the end of a file.
+    pub fn with_tail(mut self, tail: u64) -> Self {
+        self.tail = tail;
+
+        self
+    }
+
+    /// Recreate the instance with a new number of samples, and a new sample size.
+    pub fn with_samples(mut self, samples: u64, sample_size: u64) -> Self {
+        self.samples = samples;
+        self.sample_size = sample_size;
+
+        self
+    }
+
+    /// The total number of bytes this schedule reads from a file.
+    fn probed_bytes(&self) -> u64 {
+        self.head + self.tail + self.samples * self.sample_size
+    }
+
+    /// Returns whether a file of `len` bytes should be probed.
+    fn applies_to(&self, len: u64) -> bool {
+        let probed = self.probed_bytes();
+        probed > 0 && len > 2 * probed
+    }
+
+    /// Returns the regions (offset and length) of a file of `len` bytes that `probe` reads.
+    fn regions(&self, probe: Probe, len: u64) -> Vec<(u64, u64)> {
+        let regions = match probe {
+            Probe::Head => vec![(0, self.head)],
+            Probe::Tail => vec![(len - self.tail, self.tail)],
+            Probe::Samples => (1..=self.samples)
+                .map(|i| {
+                    let middle = len / (self.samples + 1) * i;
+                    (middle - self.sample_size / 2, self.sample_size)
+                })
+                .collect(),
+        };
+        regions.into_iter().filter(|(_, len)| *len > 0).collect()
+    }
+}
+
+impl Default for ProbeSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How many bytes of a file are hashed sequentially in each round.
+///
+/// Most candidates are eliminated after the first few rounds, so small first reads are cheap, while files that are
+/// still candidates after that are likely duplicates, and benefit from large sequential reads. By default, chunks
+/// start at 4 KiBs, and are 4 times larger each round, up to 16 MiBs.
+#[derive(Clone, Debug)]
+pub struct ChunkSchedule {
+    /// The size of the first chunk.
+    start: u64,
+    /// How much larger each chunk is compared to the previous one.
+    growth: u64,
+    /// The maximum size of a chunk.
+    max: u64,
+}
+
+impl ChunkSchedule {
+    /// Create a schedule that always hashes `size` bytes per round.
+    pub fn fixed(size: u64) -> Self {
+        Self::geometric(size, 1, size)
+    }
+
+    /// Create a schedule whose chunks start at `start` bytes and get `growth` times larger each round, up to `max`
+    /// bytes.
+    pub fn geometric(start: u64, growth: u64, max: u64) -> Self {
+        let start = start.max(1);
+        Self { start, growth: growth.max(1), max: max.max(start) }
+    }
+
+    /// Returns the number of bytes to hash in the given round (starting at 0).
+    fn chunk_size(&self, round: u32) -> u64 {
+        self.growth
+            .checked_pow(round)
+            .and_then(|growth| growth.checked_mul(self.start))
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+impl Default for ChunkSchedule {
+    fn default() -> Self {
+        Self::geometric(4 * 1024, 4, 16 * 1024 * 1024)
+    }
+}
+
+/// The steps of a [`ProbeSchedule`], in order.
+#[derive(Clone, Copy, Debug)]
+enum Probe {
+    Head,
+    Tail,
+    Samples,
+}
+
+impl Probe {
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::Head => Some(Self::Tail),
+            Self::Tail => Some(Self::Samples),
+            Self::Samples => None,
+        }
+    }
+
+    /// Encode an optional probe as a number, see [`ProgressiveHasher::encode`].
+    fn encode(probe: Option<Self>) -> u64 {
+        match probe {
+            None => 0,
+            Some(Self::Head) => 1,
+            Some(Self::Tail) => 2,
+            Some(Self::Samples) => 3,
+        }
+    }
+
+    fn decode(probe: u64) -> Option<Option<Self>> {
+        match probe {
+            0 => Some(None),
+            1 => Some(Some(Self::Head)),
+            2 => Some(Some(Self::Tail)),
+            3 => Some(Some(Self::Samples)),
+            _ => None,
+        }
+    }
+}
+
+/// How files are read while they are hashed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashBackend {
+    /// Blocking reads, one file at a time per hasher thread.
+    #[default]
+    Std,
+    /// Reads submitted through io_uring, which keeps up to `queue_depth` reads of 128 KiBs in flight per hasher
+    /// thread, across all the files the thread is hashing. Fast storage, such as NVMe drives and arrays of them, needs
+    /// many concurrent reads to reach its full throughput.
+    ///
+    /// Falls back to [`HashBackend::Std`] if io_uring is not available (e.g. it is disabled by the kernel).
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    IoUring {
+        /// The maximum number of reads in flight per hasher thread.
+        queue_depth: u32,
+    },
+}
+
+/// The configuration shared by all hashers.
+#[derive(Clone, Debug)]
+pub(crate) struct HasherConfig {
+    pub(crate) probes: ProbeSchedule,
+    pub(crate) chunks: ChunkSchedule,
+    pub(crate) backend: HashBackend,
+    pub(crate) cache: CacheMode,
+    pub(crate) throttle: Throttle,
+    /// Whether hasher threads use the idle I/O scheduling class.
+    pub(crate) idle_io_priority: bool,
+    /// Sequential chunks of at least this many bytes are hashed by multiple threads.
+    #[cfg(feature = "parallel")]
+    pub(crate) parallel_threshold: u64,
+}
+
+// only derivable without the `parallel` feature
+#[cfg_attr(not(feature = "parallel"), allow(clippy::derivable_impls))]
+impl Default for HasherConfig {
+    fn default() -> Self {
+        Self {
+            probes: ProbeSchedule::default(),
+            chunks: ChunkSchedule::default(),
+            backend: HashBackend::default(),
+            cache: CacheMode::default(),
+            throttle: Throttle::default(),
+            idle_io_priority: false,
+            #[cfg(feature = "parallel")]
+            parallel_threshold: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// The size of the buffer used to read files.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The regions of a file that are read in a round of hashing, see [`ProgressiveHasher::next_round`].
+#[derive(Debug)]
+pub(crate) struct Round {
+    /// Whether the regions are hashed sequentially, as opposed to being probes.
+    sequential: bool,
+    /// The offset and length of each region, in the order in which they are hashed.
+    regions: Vec<(u64, u64)>,
+}
+
+impl Round {
+    /// The offset and length of each region, in the order in which they must be hashed.
+    pub(crate) fn regions(&self) -> &[(u64, u64)] {
+        &self.regions
+    }
+
+    /// The number of bytes read in this round.
+    pub(crate) fn len(&self) -> u64 {
+        self.regions.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Whether the regions are hashed sequentially, as opposed to being probes.
+    #[cfg(any(feature = "parallel", all(target_os = "linux", feature = "io-uring")))]
+    pub(crate) fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+}
+
+/// Partial hashes are derived from the probed and sequentially hashed content, along with the size of the file, so
+/// that they never collide with the hash of an entire file, or with the partial hash of a file of a different size.
+const PARTIAL_HASH_CONTEXT: &str = "duped v1 partial file hash";
 
 /// A hasher that can be used to hash a file progressively.
+///
+/// A blake3 hasher takes almost 2 KiBs, and a search can keep millions of hashers around between rounds, so blake3
+/// hashers are only allocated while they are needed: the hasher of a probe only lives during its round, and the
+/// sequential hasher is only allocated once sequential hashing starts, which most files never get to.
 pub struct ProgressiveHasher {
-    /// Our hasher instance that might have some data in it already.
-    hasher: blake3::Hasher,
+    /// Our hasher instance that might have some data in it already, or `None` if sequential hashing didn't start.
+    hasher: Option<Box<blake3::Hasher>>,
+    /// Hashes the regions of the current probe, until they are all hashed.
+    probe_hasher: Option<Box<blake3::Hasher>>,
+    /// Chains the hashes of the probes that were fully hashed.
+    probe_hash: blake3::Hash,
+    /// How many bytes of the current probe are left to hash.
+    probe_left: u64,
+    /// The next probe to hash, or `None` if probing is over.
+    next_probe: Option<Probe>,
     /// The file we are hashing chunk by chunk.
     file_path: FilePath,
     /// How much of a file we already hashed.
     len_hashed: u64,
+    /// How many chunks of the file we already hashed.
+    chunks_hashed: u32,
+    /// How many bytes were hashed in the current round.
+    round_bytes: u64,
+    /// How many of `round_bytes` were holes, which were hashed without being read.
+    round_holes: u64,
 }
 
-// 16 KiBs
-const MIN_TO_READ: u64 = 16 * 1024 * 1024;
-
 impl ProgressiveHasher {
     /// Creates a new instance with a given [`FilePath`].
     ///
@@ -24,7 +305,18 @@ impl ProgressiveHasher {
     ///
     /// * `file_path` - The path of the file this instance will progressively hash.
     pub fn new(file_path: FilePath) -> Self {
-        Self { hasher: Default::default(), file_path, len_hashed: 0 }
+        Self {
+            hasher: None,
+            probe_hasher: None,
+            probe_hash: blake3::Hash::from_bytes([0; 32]),
+            probe_left: 0,
+            next_probe: Some(Probe::Head),
+            file_path,
+            len_hashed: 0,
+            chunks_hashed: 0,
+            round_bytes: 0,
+            round_holes: 0,
+        }
     }
 
     /// Gets the inner file path.
@@ -32,61 +324,453 @@ impl ProgressiveHasher {
         &self.file_path
     }
 
-    /// Hashes the next 16KiBs of the file.
+    /// How many bytes were hashed in the current (or last) round, including the probes.
+    pub(crate) fn round_bytes(&self) -> u64 {
+        self.round_bytes
+    }
+
+    /// How many bytes were read from the file in the current (or last) round, i.e. [`Self::round_bytes`] without the
+    /// holes.
+    pub(crate) fn round_bytes_read(&self) -> u64 {
+        self.round_bytes - self.round_holes
+    }
+
+    /// How many bytes of the file are left to hash sequentially.
+    pub(crate) fn bytes_left(&self) -> u64 {
+        self.file_path.size() - self.len_hashed
+    }
+
+    /// Hashes the next probe of the file, or the next chunk of the file if probing is over.
     ///
     /// Note, this method is going to open a _new_ file handle.
-    pub fn update(&mut self) -> io::Result<()> {
-        let leftover = self.file_path.metadata().len() - self.len_hashed;
-        let bytes_to_take = leftover.min(MIN_TO_READ);
+    pub(crate) fn update(&mut self, config: &HasherConfig) -> io::Result<()> {
+        let round = self.next_round(config);
+        config.throttle.acquire(round.len(), 1);
+
+        #[cfg(target_os = "linux")]
+        if config.cache == CacheMode::Direct {
+            if let Some(file) = cache::open_direct(&self.file_path.path())? {
+                let sparse = self.file_path.is_sparse();
+                for &(offset, len) in round.regions() {
+                    for segment in sparse::segments(&file, offset, len, sparse) {
+                        if segment.hole {
+                            self.feed_hole(&round, segment.len);
+                        } else {
+                            cache::read_direct(&file, segment.offset, segment.len, |data| {
+                                self.feed(&round, data)
+                            })?;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        let mut file = open_regular_file(&self.file_path.path())?;
 
-        let mut file = std::fs::File::open(self.file_path.path())?;
+        self.read_round(&mut file, &round, config.cache)
+    }
 
-        file.seek(io::SeekFrom::Start(self.len_hashed))?;
-        let reader = file.take(bytes_to_take);
+    /// Reads the regions of `round` from `file`, and hashes them.
+    ///
+    /// The holes of sparse files are hashed as zeros, without being read.
+    pub(crate) fn read_round(
+        &mut self,
+        file: &mut File,
+        round: &Round,
+        cache: CacheMode,
+    ) -> io::Result<()> {
+        if cache != CacheMode::Cached && round.sequential {
+            cache::advise_sequential(file);
+        }
 
-        self.hasher.update_reader(reader)?;
+        let sparse = self.file_path.is_sparse();
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        for &(offset, len) in round.regions() {
+            for segment in sparse::segments(file, offset, len, sparse) {
+                if segment.hole {
+                    self.feed_hole(round, segment.len);
+                    continue;
+                }
 
-        self.len_hashed += bytes_to_take;
+                file.seek(io::SeekFrom::Start(segment.offset))?;
+                let mut left = segment.len;
+                while left > 0 {
+                    let to_read = left.min(READ_BUFFER_SIZE as u64) as usize;
+                    let read = match file.read(&mut buffer[..to_read]) {
+                        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(read) => read,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    self.feed(round, &buffer[..read]);
+                    left -= read as u64;
+                }
+            }
+            if cache != CacheMode::Cached {
+                cache::drop_from_cache(file, offset, len);
+            }
+        }
 
         Ok(())
     }
 
-    /// Returns whether the hasher finished hashing the entire input.
+    /// Plans the next round of hashing: the next probe of the file, or the next chunk of the file if probing is over.
+    ///
+    /// The regions of the round must then be read in order, and passed to [`Self::feed`].
+    pub(crate) fn next_round(&mut self, config: &HasherConfig) -> Round {
+        self.round_bytes = 0;
+        self.round_holes = 0;
+        let len = self.file_path.size();
+        if !config.probes.applies_to(len) {
+            self.next_probe = None;
+        }
+        while let Some(probe) = self.next_probe {
+            self.next_probe = probe.next();
+            let regions = config.probes.regions(probe, len);
+            if !regions.is_empty() {
+                let round = Round { sequential: false, regions };
+                self.probe_left = round.len();
+                return round;
+            }
+        }
+
+        let leftover = len - self.len_hashed;
+        let bytes_to_take = leftover.min(config.chunks.chunk_size(self.chunks_hashed));
+        self.chunks_hashed += 1;
+
+        Round { sequential: true, regions: vec![(self.len_hashed, bytes_to_take)] }
+    }
+
+    /// Hashes the next `data` read from the regions of `round`.
+    pub(crate) fn feed(&mut self, round: &Round, data: &[u8]) {
+        self.feed_with(round, data, |hasher, data| {
+            hasher.update(data);
+        });
+    }
+
+    /// Hashes `len` zeros in place of a hole of the file, which was not read.
+    pub(crate) fn feed_hole(&mut self, round: &Round, len: u64) {
+        self.round_holes += len;
+        sparse::feed_zeros(len, |zeros| self.feed(round, zeros));
+    }
+
+    /// Same as [`Self::feed`], but hashes `data` using the threads of the current rayon pool.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn feed_parallel(&mut self, round: &Round, data: &[u8]) {
+        self.feed_with(round, data, |hasher, data| {
+            hasher.update_rayon(data);
+        });
+    }
+
+    fn feed_with(
+        &mut self,
+        round: &Round,
+        data: &[u8],
+        update: impl FnOnce(&mut blake3::Hasher, &[u8]),
+    ) {
+        self.round_bytes += data.len() as u64;
+        if round.sequential {
+            update(self.hasher.get_or_insert_with(Default::default), data);
+            self.len_hashed += data.len() as u64;
+            return;
+        }
+
+        update(self.probe_hasher.get_or_insert_with(Default::default), data);
+        self.probe_left -= data.len() as u64;
+        if self.probe_left == 0 {
+            let probe = self.probe_hasher.take().expect("probe hasher exists").finalize();
+            let mut chained = blake3::Hasher::new();
+            chained.update(self.probe_hash.as_bytes());
+            chained.update(probe.as_bytes());
+            self.probe_hash = chained.finalize();
+        }
+    }
+
+    /// Returns the current hash, and whether the hasher finished hashing the entire input.
+    ///
+    /// If the hasher is not done, the hash is a partial hash: two files have the same partial hash only if they have
+    /// the same size, and the same content in the regions that were hashed so far.
     pub fn current_hash(&self) -> (blake3::Hash, bool) {
-        let hash = self.hasher.finalize();
-        let done = self.len_hashed == self.file_path.metadata().len();
+        let len = self.file_path.size();
+        let done = self.next_probe.is_none() && self.len_hashed == len;
+        let sequential = match &self.hasher {
+            Some(hasher) => hasher.finalize(),
+            None => blake3::hash(&[]),
+        };
+        if done {
+            return (sequential, done);
+        }
 
-        (hash, done)
+        let mut partial = blake3::Hasher::new_derive_key(PARTIAL_HASH_CONTEXT);
+        partial.update(&len.to_le_bytes());
+        partial.update(self.probe_hash.as_bytes());
+        partial.update(sequential.as_bytes());
+
+        (partial.finalize(), done)
+    }
+
+    /// Append the state of the hasher to `out`, so that it can be spilled to disk between rounds.
+    ///
+    /// Returns `false` if the state can't be encoded, which is the case once sequential hashing started, or in the
+    /// middle of a round.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) -> bool {
+        if self.hasher.is_some() || self.probe_hasher.is_some() || self.probe_left > 0 {
+            return false;
+        }
+
+        self.file_path.encode(out);
+        out.extend_from_slice(self.probe_hash.as_bytes());
+        spill::put_u64(out, Probe::encode(self.next_probe));
+        spill::put_u64(out, self.chunks_hashed.into());
+
+        true
+    }
+
+    /// Decode a hasher encoded with [`Self::encode`].
+    pub(crate) fn decode(input: &mut &[u8], dirs: &mut DirInterner) -> Option<Self> {
+        let file_path = FilePath::decode(input, dirs)?;
+        let (probe_hash, rest) = input.split_first_chunk::<32>()?;
+        *input = rest;
+
+        Some(Self {
+            hasher: None,
+            probe_hasher: None,
+            probe_hash: blake3::Hash::from_bytes(*probe_hash),
+            probe_left: 0,
+            next_probe: Probe::decode(spill::take_u64(input)?)?,
+            file_path,
+            len_hashed: 0,
+            chunks_hashed: spill::take_u64(input)?.try_into().ok()?,
+            round_bytes: 0,
+            round_holes: 0,
+        })
     }
 }
 
-/// A set of hashers.
-#[derive(Default)]
+/// Open `path` for reading, and make sure it is (still) a regular file.
+///
+/// The file might have been replaced by something else since it was first visited. On unix, the file is opened in
+/// non-blocking mode, so that opening a named pipe doesn't block forever. Reads are blocking once the file is known to
+/// be a regular file.
+pub(crate) fn open_regular_file(path: &Path) -> io::Result<File> {
+    open_regular_file_with(path, 0)
+}
+
+/// Same as [`open_regular_file`], but with additional `flags` passed to `open(2)` on unix (they are ignored elsewhere).
+pub(crate) fn open_regular_file_with(path: &Path, flags: i32) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.custom_flags(libc::O_NONBLOCK | flags);
+    }
+    #[cfg(not(unix))]
+    let _ = flags;
+
+    let file = options.open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a regular file"));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+
+        // non-blocking reads of regular files might fail with `EAGAIN` when submitted through io_uring
+        // SAFETY: the file descriptor is valid for the lifetime of `file`.
+        unsafe {
+            let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+            if flags < 0
+                || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(file)
+}
+
+/// A set of hashers, grouped by their current hash.
+///
+/// Only the number of hashers that share a hash is kept per hash (or rather, per 64 bits of hash), the hashers
+/// themselves are kept in a [`CandidateList`], which might spill them to disk.
 pub(crate) struct HasherSet {
-    inner: std::collections::HashMap<blake3::Hash, Vec<ProgressiveHasher>>,
+    counts: HashMap<u64, Sides>,
+    hashers: CandidateList,
+    elimination: Elimination,
+}
+
+/// Which candidates are eliminated after a round.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Elimination {
+    /// Candidates whose hash isn't shared by any other candidate.
+    Unique,
+    /// Candidates whose hash isn't shared by a candidate of the other side, where the reference side is made of the
+    /// roots from `first_reference_root` on (see [`crate::DeduperBuilder::reference_roots`]).
+    Uncovered { first_reference_root: usize },
+    /// No candidate is eliminated, so all files are hashed entirely (see [`crate::DeduperBuilder::hash_all_files`]).
+    Never,
+}
+
+/// How many hashers of each side share a hash.
+#[derive(Default)]
+struct Sides {
+    sources: u32,
+    references: u32,
 }
 
 impl HasherSet {
+    /// Create an empty set, which spills its hashers to disk according to `spill`, and eliminates hashers according to
+    /// `elimination`.
+    pub(crate) fn new(spill: Option<SpillConfig>, elimination: Elimination) -> Self {
+        Self { counts: HashMap::new(), hashers: CandidateList::new(spill), elimination }
+    }
+
     /// Inserts the given hasher into the set.
     pub(crate) fn insert(&mut self, hasher: ProgressiveHasher) {
-        self.inner.entry(hasher.current_hash().0).or_default().push(hasher);
+        let sides = self.counts.entry(Self::key(&hasher)).or_default();
+        let is_reference = match self.elimination {
+            Elimination::Uncovered { first_reference_root } => {
+                hasher.file_path().root_index() >= first_reference_root
+            }
+            Elimination::Unique | Elimination::Never => false,
+        };
+        if is_reference {
+            sides.references += 1;
+        } else {
+            sides.sources += 1;
+        }
+        self.hashers.push(hasher);
     }
 
-    /// Returns all hashers that still need some work.
+    /// Pass the hashers that don't share their hash with any other hasher to `finished`, and return the others, which
+    /// still need some work.
+    ///
+    /// With [`Elimination::Uncovered`], a hasher is only kept if its hash is shared by both a source and a reference file:
+    /// two source files that share a hash are irrelevant if no reference file has it too, and vice versa. With
+    /// [`Elimination::Never`], all hashers are kept.
+    ///
+    /// Two hashers that only share 64 bits of their hash are both returned, which only costs them another round.
     pub(crate) fn filter_unfinished_duplicates(
         self,
-    ) -> (Vec<ProgressiveHasher>, Vec<ProgressiveHasher>) {
-        let mut finished_hashers = vec![];
-        let mut output_hashers = vec![];
-        for (_, mut hashers) in self.inner {
-            if hashers.len() == 1 {
-                // safe to remove since the len of the vec is 1
-                finished_hashers.push(hashers.remove(0));
+        mut finished: impl FnMut(ProgressiveHasher),
+    ) -> CandidateList {
+        let mut unfinished = self.hashers.empty_like();
+        for hasher in self.hashers.into_hashers() {
+            let sides = &self.counts[&Self::key(&hasher)];
+            let keep = match self.elimination {
+                Elimination::Unique => sides.sources > 1,
+                Elimination::Uncovered { .. } => sides.sources > 0 && sides.references > 0,
+                Elimination::Never => true,
+            };
+            if keep {
+                unfinished.push(hasher);
             } else {
-                output_hashers.extend(hashers);
+                finished(hasher);
+            }
+        }
+
+        unfinished
+    }
+
+    fn key(hasher: &ProgressiveHasher) -> u64 {
+        let hash = hasher.current_hash().0;
+        u64::from_le_bytes(*hash.as_bytes().first_chunk().expect("hashes are 32 bytes"))
+    }
+}
+
+/// Helpers for the tests of the modules that hash files.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Create a hasher of the file at `path`, which must exist.
+    pub(crate) fn file_hasher(path: &Path) -> ProgressiveHasher {
+        let metadata = path.symlink_metadata().unwrap();
+        ProgressiveHasher::new(FilePath::new(path.to_owned().into(), &metadata))
+    }
+
+    /// Write `data` to a file named `name` in `dir`, and create a hasher of it.
+    pub(crate) fn hasher(dir: &Path, name: &str, data: &[u8]) -> ProgressiveHasher {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        file_hasher(&path)
+    }
+
+    /// Hash the rest of the file of `hasher`, and return the hash of its entire contents.
+    pub(crate) fn finish(hasher: &mut ProgressiveHasher, config: &HasherConfig) -> blake3::Hash {
+        loop {
+            hasher.update(config).unwrap();
+            if let (hash, true) = hasher.current_hash() {
+                return hash;
             }
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{finish, hasher};
+    use super::*;
+
+    #[test]
+    fn tail_probe_tells_files_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![7; 1024 * 1024];
+        let mut other = data.clone();
+        *other.last_mut().unwrap() = 8;
+        let mut a = hasher(dir.path(), "a", &data);
+        let mut b = hasher(dir.path(), "b", &other);
+        let config = HasherConfig::default();
+
+        a.update(&config).unwrap();
+        b.update(&config).unwrap();
+        assert_eq!(a.current_hash(), b.current_hash());
+
+        a.update(&config).unwrap();
+        b.update(&config).unwrap();
+        let (hash_a, done_a) = a.current_hash();
+        let (hash_b, done_b) = b.current_hash();
+        assert_ne!(hash_a, hash_b);
+        assert!(!done_a && !done_b);
+    }
+
+    #[test]
+    fn probing_produces_content_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+        for probes in [ProbeSchedule::new(), ProbeSchedule::disabled()] {
+            let config = HasherConfig { probes, ..Default::default() };
+            let mut h = hasher(dir.path(), "a", &data);
+            assert_eq!(finish(&mut h, &config), blake3::hash(&data));
+        }
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let schedule = ChunkSchedule::geometric(4, 4, 100);
+        let sizes: Vec<_> = (0..5).map(|i| schedule.chunk_size(i)).collect();
+        assert_eq!(sizes, [4, 16, 64, 100, 100]);
+        assert_eq!(schedule.chunk_size(u32::MAX), 100);
+        assert_eq!(ChunkSchedule::fixed(10).chunk_size(3), 10);
+    }
 
-        (finished_hashers, output_hashers)
+    #[test]
+    fn partial_hashes_depend_on_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HasherConfig {
+            probes: ProbeSchedule::disabled(),
+            chunks: ChunkSchedule::fixed(1024),
+            ..Default::default()
+        };
+        let data = vec![1; 2048];
+        let mut prefix = hasher(dir.path(), "prefix", &data[..1024]);
+        let mut whole = hasher(dir.path(), "whole", &data);
+        prefix.update(&config).unwrap();
+        whole.update(&config).unwrap();
+        assert_ne!(prefix.current_hash().0, whole.current_hash().0);
     }
 }
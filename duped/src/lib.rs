@@ -10,27 +10,60 @@
 //! ```
 
 use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
     io,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
         mpsc::{self, Receiver, SyncSender},
         Arc,
     },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 pub use blake3;
-use tracing::error;
-use walkdir::WalkDir;
+use tracing::{error, warn};
 
+mod cache;
+mod combinator;
 mod duplicates;
 mod file;
+mod filter;
 mod hasher;
+mod manifest;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod report;
+mod schedule;
+mod sparse;
+mod spill;
+mod stats;
+mod throttle;
 mod traits;
-
-pub use duplicates::{DeduperResult, FileEntries, FileEntry};
-use file::FilePath;
-use hasher::ProgressiveHasher;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod walk;
+
+pub use cache::CacheMode;
+pub use combinator::{And, Not, Or};
+pub use duplicates::{
+    DeduperResult, DuplicateOrder, FileEntries, FileEntry, RootFilter, SkipReason, SkippedEntry,
+};
+pub use file::FileKind;
+use file::{CompactPath, FilePath};
+pub use filter::{GlobFilter, RegexFilter};
+pub use hasher::{ChunkSchedule, HashBackend, ProbeSchedule};
+use hasher::{Elimination, HasherConfig, ProgressiveHasher};
+pub use manifest::{Manifest, ManifestDiff, ManifestEntry};
+pub use report::{
+    DirectoryPair, DirectoryReport, DirectoryWaste, DuplicateTree, SimilarDirectories,
+};
+pub use schedule::ReadOrder;
+use spill::{CandidateList, SpillConfig};
+pub use stats::{RoundStats, ScanStats};
+pub use throttle::Throttle;
 pub use traits::*;
+use walk::{WalkEntry, WalkOptions, Walker};
 
 /// File deduplicator.
 #[derive(Debug)]
@@ -49,51 +82,122 @@ impl Deduper {
         &self.inner.roots
     }
 
-    /// Collect all files and their metadata into a vector based on a given filter.
-    fn collect_files(
+    /// Walk the roots, and hand the files that pass the filter over to `dispatcher` as soon as they are found.
+    ///
+    /// Returns the files that were skipped because they are not regular files, or because they couldn't be read, the
+    /// directories in which the filter or the walk options excluded a file or a subdirectory, and whether the filter
+    /// stopped the walk.
+    fn walk(
         &self,
         mut file_filter: impl DeduperFileFilter,
-    ) -> (Vec<ProgressiveHasher>, bool) {
-        let mut stopped = false;
-        let mut files = vec![];
-        'main: for root in &self.inner.roots {
-            for entry in WalkDir::new(root) {
-                let path = match entry {
-                    Ok(p) => p.into_path(),
-                    Err(e) => {
-                        error!(error = %e, "io error occured while waking dirs");
-                        continue;
-                    }
-                };
-                if path.is_dir() || path.is_symlink() {
+        dispatcher: &mut Dispatcher<'_>,
+        hooks: &dyn DeduperFindHook,
+    ) -> (Vec<SkippedEntry>, HashSet<PathBuf>, bool) {
+        let mut skipped = vec![];
+        let mut filtered = HashSet::new();
+        let mut incomplete = |dir: Option<&Path>| {
+            if let Some(dir) = dir.filter(|dir| !filtered.contains(*dir)) {
+                filtered.insert(dir.to_owned());
+            }
+        };
+        let mut current_root = None;
+        let mut walker = Walker::new(&self.inner.roots, self.inner.walk.clone());
+        let (mut dirs, mut files) = (0, 0);
+        let stopped = loop {
+            // don't keep the files we found so far waiting while the walker is busy
+            if !walker.is_ready() {
+                dispatcher.flush();
+            }
+            let Some(entry) = walker.next() else {
+                break false;
+            };
+            match entry {
+                WalkEntry::Dir(_) => dirs += 1,
+                WalkEntry::File { .. } => files += 1,
+                WalkEntry::Error { .. } | WalkEntry::Pruned { .. } => {}
+            }
+            if (dirs + files) % WALK_PROGRESS_INTERVAL == 0 {
+                hooks.walk_progress(dirs, files, false);
+            }
+
+            let root = match &entry {
+                WalkEntry::Dir(dir) => dir.root(),
+                WalkEntry::File { root, .. } => *root,
+                WalkEntry::Error { path, error } => {
+                    error!(error = %error, path = %path.display(), "io error occured while walking dirs");
+                    skipped.push(SkippedEntry::new(path.clone(), SkipReason::Io(error.kind())));
                     continue;
                 }
+                WalkEntry::Pruned { dir } => {
+                    incomplete(Some(dir));
+                    continue;
+                }
+            };
+            if current_root != Some(root) {
+                current_root = Some(root);
+                file_filter.root_entered(&self.inner.roots[root]);
+            }
 
-                let file_path = match FilePath::try_new(path.clone()) {
-                    Ok(md) => md,
-                    Err(e) => {
-                        error!(error = %e, path = %path.display(), "io error when reading metadata");
+            let action = match entry {
+                WalkEntry::Dir(dir) => {
+                    let action = file_filter.handle_dir(dir.path(), dir.metadata());
+                    match action {
+                        FilterAction::Continue(FileAction::Include) => walker.descend(dir),
+                        FilterAction::Continue(FileAction::Exclude) => {
+                            incomplete(dir.path().parent())
+                        }
+                        FilterAction::Break(()) => {}
+                    }
+                    action
+                }
+                WalkEntry::File { root, path, parent, metadata } => {
+                    let kind = FileKind::from_file_type(metadata.file_type());
+                    if kind != FileKind::Regular {
+                        skipped.push(SkippedEntry::new(path, SkipReason::NotRegularFile(kind)));
                         continue;
                     }
-                };
 
-                match file_filter.handle_file(file_path.path(), file_path.metadata()) {
-                    FilterAction::Continue(FileAction::Exclude) => {}
-                    FilterAction::Continue(FileAction::Include) => {
-                        files.push(ProgressiveHasher::new(file_path));
-                    }
-                    FilterAction::Break(_) => {
-                        stopped = true;
-                        break 'main;
+                    let action = file_filter.handle_file(&path, &metadata);
+                    match action {
+                        FilterAction::Continue(FileAction::Include) => {
+                            let path = CompactPath::new(parent, path);
+                            dispatcher.add(FilePath::new(path, &metadata).with_root(root));
+                        }
+                        FilterAction::Continue(FileAction::Exclude) => incomplete(path.parent()),
+                        FilterAction::Break(()) => {}
                     }
+                    action
                 }
+                WalkEntry::Error { .. } | WalkEntry::Pruned { .. } => {
+                    unreachable!("errors and pruned directories are handled above")
+                }
+            };
+            if action.is_break() {
+                break true;
             }
-        }
+        };
+        hooks.walk_progress(dirs, files, true);
 
-        (files, stopped)
+        (skipped, filtered, stopped)
+    }
+
+    /// Which candidates are eliminated after each round.
+    fn elimination(&self) -> Elimination {
+        match (self.inner.hash_all_files, self.inner.first_reference_root) {
+            (true, _) => Elimination::Never,
+            (false, Some(first_reference_root)) => Elimination::Uncovered { first_reference_root },
+            (false, None) => Elimination::Unique,
+        }
     }
 
     /// Finds and returns duplicated files on disk.
+    ///
+    /// Roots are walked in parallel, and files are hashed while the walk is still in progress. Files are hashed in
+    /// rounds: after each round, files whose (partial) hash is unique are set aside, while the others are hashed
+    /// further, until they are hashed in their entirety.
+    ///
+    /// If `file_filter` stops the walk, the files found so far are still processed, and the result is marked as
+    /// partial.
     pub fn find(
         &self,
         file_filter: impl DeduperFileFilter,
@@ -101,58 +205,54 @@ impl Deduper {
     ) -> io::Result<DeduperResult> {
         let hooks = Arc::new(find_hook) as Arc<dyn DeduperFindHook>;
 
-        let (mut collected_files, stopped) = self.collect_files(file_filter);
-        let collected_files_len = collected_files.len();
-
-        if stopped || collected_files_len == 0 {
-            return Ok(Default::default());
-        }
-
         let num_threads = num_cpus::get();
 
+        let mut config = self.inner.hasher.clone();
+        if self.inner.hash_all_files {
+            // probes only help to eliminate candidates early, and none are eliminated
+            config.probes = ProbeSchedule::disabled();
+        }
+        let shared = Arc::new(HasherShared {
+            config,
+            #[cfg(feature = "parallel")]
+            cpus: parallel::Cpus::new(num_threads),
+        });
         let (result_tx, result_rx) = mpsc::sync_channel(num_threads);
         let mut threads = Vec::with_capacity(num_threads);
-        for i in 0..num_threads {
+        for _ in 0..num_threads {
             let (thread_tx, thread_rx) = mpsc::sync_channel(1);
             let result_tx = result_tx.clone();
-            let handle = std::thread::spawn(move || hasher_task(i, thread_rx, result_tx));
+            let shared = Arc::clone(&shared);
+            let handle = std::thread::spawn(move || hasher_task(thread_rx, result_tx, shared));
             threads.push((handle, thread_tx));
         }
 
         let (collector_tx, collector_rx) = mpsc::sync_channel(1);
-        hooks.files_selected(collected_files_len);
-        let collector = std::thread::spawn(move || {
-            collect(collected_files_len, result_rx, collector_tx, hooks)
-        });
-        drop(result_tx);
-
+        let collector = {
+            let hooks = Arc::clone(&hooks);
+            let spill = self.inner.spill.clone();
+            let elimination = self.elimination();
+            std::thread::spawn(move || collect(result_rx, collector_tx, hooks, spill, elimination))
+        };
+
+        let walk_start = Instant::now();
+        let mut dispatcher =
+            Dispatcher::new(&threads, self.inner.read_order, self.inner.hash_all_files);
+        let (skipped, filtered, stopped) = self.walk(file_filter, &mut dispatcher, &*hooks);
+        let walk_duration = walk_start.elapsed();
+        dispatcher.finish(&result_tx, &*hooks);
+
+        let mut round = 1;
         loop {
-            let chunk_size = (collected_files.len() / threads.len()).max(1);
-            let mut hashers_to_be_sent = Vec::with_capacity(threads.len());
-            for _ in 0..threads.len() {
-                hashers_to_be_sent.push(Vec::with_capacity(chunk_size));
-            }
-            let mut i = 0;
-            while !collected_files.is_empty() {
-                let size = chunk_size.min(collected_files.len());
-                let hashers = collected_files.drain(..size);
-                hashers_to_be_sent[i % threads.len()].extend(hashers);
-
-                i += 1;
-            }
-
-            for (i, hashers) in hashers_to_be_sent.into_iter().enumerate() {
-                if threads[i].1.send(hashers).is_err() {
-                    panic!("thread died?");
-                }
-            }
-
-            let Ok(files) = collector_rx.recv() else {
-                todo!("handle collector dying");
+            let Ok(collected_files) = collector_rx.recv() else {
+                // the collector only exits early if it panicked, and the hasher threads exit once their senders are
+                // dropped
+                drop(threads);
+                return Err(io::Error::other("collector thread exited"));
             };
 
             // no more files to hash, so we can clean up and return
-            if files.is_empty() {
+            if collected_files.is_empty() {
                 // XXX: why doesn't rust "drop in place" rx if I use `_`?
                 for (t, rx) in threads {
                     drop(rx);
@@ -160,18 +260,194 @@ impl Deduper {
                 }
 
                 let mut duplicates = collector.join().expect("failed to join with collector");
+                duplicates.add_skipped(skipped);
+                duplicates.set_filtered_dirs(filtered);
+                duplicates.stats_mut().walk_duration = walk_duration;
+                duplicates.set_first_reference_root(self.inner.first_reference_root);
+                if self.inner.hash_all_files {
+                    duplicates.set_fully_hashed();
+                }
                 if stopped {
                     duplicates.set_partial();
                 }
 
                 return Ok(duplicates);
-            } else {
-                collected_files = files;
+            }
+
+            round += 1;
+            hooks.round_started(round, collected_files.len(), collected_files.bytes_left());
+            let dispatched = dispatch(collected_files, &threads, self.inner.read_order);
+            if result_tx.send(Collected::Dispatched(dispatched)).is_err() {
+                error!("collector is gone");
             }
         }
     }
 }
 
+/// Send the candidates of a round to the hasher threads, and return how many were sent.
+///
+/// Unless files are hashed in the order in which they are found, all candidates are read back in memory, so that they
+/// can be sorted.
+fn dispatch(candidates: CandidateList, threads: &[HasherThread], order: ReadOrder) -> usize {
+    let mut dispatched = 0;
+    let mut send = |thread: usize, batch: Vec<ProgressiveHasher>| {
+        dispatched += batch.len();
+        if threads[thread].1.send(batch).is_err() {
+            panic!("thread died?");
+        }
+    };
+
+    if order != ReadOrder::Discovery {
+        let hashers = candidates.into_hashers().collect();
+        for (thread, batch) in
+            schedule::distribute(hashers, threads.len(), order).into_iter().enumerate()
+        {
+            if !batch.is_empty() {
+                send(thread, batch);
+            }
+        }
+        return dispatched;
+    }
+
+    let mut next_thread = 0;
+    let mut batch = Vec::with_capacity(DISPATCH_BATCH_SIZE);
+    for hasher in candidates.into_hashers() {
+        batch.push(hasher);
+        if batch.len() >= DISPATCH_BATCH_SIZE {
+            send(
+                next_thread,
+                std::mem::replace(&mut batch, Vec::with_capacity(DISPATCH_BATCH_SIZE)),
+            );
+            next_thread = (next_thread + 1) % threads.len();
+        }
+    }
+    if !batch.is_empty() {
+        send(next_thread, batch);
+    }
+
+    dispatched
+}
+
+/// A hasher thread, and the channel used to send it work.
+type HasherThread = (JoinHandle<()>, SyncSender<Vec<ProgressiveHasher>>);
+
+/// How many entries are walked between two calls to [`DeduperFindHook::walk_progress`].
+const WALK_PROGRESS_INTERVAL: u64 = 1024;
+
+/// How many files are sent to a hasher thread at once, during the walk.
+const DISPATCH_BATCH_SIZE: usize = 16;
+
+/// Groups the files found during the walk by size, and sends the files that share their size with other files to the
+/// hasher threads.
+///
+/// A file with a unique size can't have duplicates, so it is never read (unless all files are hashed, see
+/// [`DeduperBuilder::hash_all_files`]).
+///
+/// Unless files are hashed in the order in which they are found, hashers are only sent to the threads once the walk is
+/// over, so that they can be sorted.
+struct Dispatcher<'a> {
+    threads: &'a [HasherThread],
+    order: ReadOrder,
+    /// The only file found so far for each size, or `None` if more than one file has that size.
+    sizes: HashMap<u64, Option<FilePath>>,
+    /// Hashers that weren't sent to a thread yet.
+    batch: Vec<ProgressiveHasher>,
+    /// The thread that gets the next batch.
+    next_thread: usize,
+    /// How many hashers were sent to the threads.
+    dispatched: usize,
+    /// The total size of the files that were added.
+    bytes: u64,
+    /// Whether files with a unique size are hashed too.
+    hash_all: bool,
+}
+
+impl<'a> Dispatcher<'a> {
+    fn new(threads: &'a [HasherThread], order: ReadOrder, hash_all: bool) -> Self {
+        Self {
+            threads,
+            order,
+            hash_all,
+            sizes: HashMap::new(),
+            batch: Vec::with_capacity(DISPATCH_BATCH_SIZE),
+            next_thread: 0,
+            dispatched: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Add a file that was selected by the filter.
+    fn add(&mut self, file_path: FilePath) {
+        self.bytes += file_path.size();
+        if self.hash_all {
+            self.push(file_path);
+            return;
+        }
+        match self.sizes.entry(file_path.size()) {
+            Entry::Vacant(entry) => {
+                entry.insert(Some(file_path));
+            }
+            Entry::Occupied(mut entry) => {
+                if let Some(first) = entry.get_mut().take() {
+                    self.push(first);
+                }
+                self.push(file_path);
+            }
+        }
+    }
+
+    fn push(&mut self, file_path: FilePath) {
+        self.batch.push(ProgressiveHasher::new(file_path));
+        if self.order == ReadOrder::Discovery && self.batch.len() >= DISPATCH_BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    /// Send the current batch to the next hasher thread.
+    fn flush(&mut self) {
+        if self.batch.is_empty() || self.order != ReadOrder::Discovery {
+            return;
+        }
+
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(DISPATCH_BATCH_SIZE));
+        self.dispatched += batch.len();
+        if self.threads[self.next_thread].1.send(batch).is_err() {
+            panic!("thread died?");
+        }
+        self.next_thread = (self.next_thread + 1) % self.threads.len();
+    }
+
+    /// Send the remaining files, let the collector know the walk is over, and `hooks` that the first round started.
+    fn finish(mut self, tx: &SyncSender<Collected>, hooks: &dyn DeduperFindHook) {
+        if self.order == ReadOrder::Discovery {
+            self.flush();
+        } else {
+            let hashers = std::mem::take(&mut self.batch);
+            self.dispatched = hashers.len();
+            for (thread, batch) in schedule::distribute(hashers, self.threads.len(), self.order)
+                .into_iter()
+                .enumerate()
+            {
+                if !batch.is_empty() && self.threads[thread].1.send(batch).is_err() {
+                    panic!("thread died?");
+                }
+            }
+        }
+        let unique: Vec<_> = self.sizes.into_values().flatten().collect();
+        let selected = self.dispatched + unique.len();
+        hooks.files_selected(selected);
+        hooks.round_started(1, selected, self.bytes);
+        for file_path in unique {
+            if tx.send(Collected::Unique(file_path)).is_err() {
+                error!("collector is gone");
+            }
+        }
+        if tx.send(Collected::Dispatched(self.dispatched)).is_err() {
+            error!("collector is gone");
+        }
+    }
+}
+
 #[derive(Debug)]
 struct DeduperInner {
     /// Where to start the search from.
@@ -179,6 +455,18 @@ struct DeduperInner {
     /// If the size of the file is under `lower_limit` bytes, it is not taken
     /// into account.
     lower_limit: Option<u64>,
+    /// Which entries are walked, and how.
+    walk: WalkOptions,
+    /// How files are hashed.
+    hasher: HasherConfig,
+    /// The order in which the files of each round are read.
+    read_order: ReadOrder,
+    /// Where and when the candidates of each round are spilled to disk, if at all.
+    spill: Option<SpillConfig>,
+    /// The index of the first reference root in `roots`, if any.
+    first_reference_root: Option<usize>,
+    /// Whether all files are hashed entirely, even if they can't have duplicates.
+    hash_all_files: bool,
 }
 
 /// A builder for [`Deduper`].
@@ -189,7 +477,25 @@ pub struct DeduperBuilder {
 impl DeduperBuilder {
     /// Create a new instance of the builder with a list of roots.
     pub fn new(roots: Vec<PathBuf>) -> Self {
-        Self { inner: DeduperInner { roots, lower_limit: None } }
+        Self {
+            inner: DeduperInner {
+                roots,
+                lower_limit: None,
+                walk: WalkOptions {
+                    threads: num_cpus::get(),
+                    max_depth: None,
+                    skip_hidden: false,
+                    same_file_system: false,
+                    respect_ignore_files: false,
+                    idle_io_priority: false,
+                },
+                hasher: HasherConfig::default(),
+                read_order: ReadOrder::default(),
+                spill: None,
+                first_reference_root: None,
+                hash_all_files: false,
+            },
+        }
     }
 
     /// Set the lower file size limit, in bytes.
@@ -201,22 +507,245 @@ impl DeduperBuilder {
         self
     }
 
+    /// Skip files and directories that are listed in `.gitignore`, `.ignore`, and `.dupedignore` files.
+    ///
+    /// Ignore files use the `.gitignore` syntax (including `!` negations), and apply to the directory they are in and
+    /// all its subdirectories. Patterns in deeper directories take precedence, and within the same directory
+    /// `.dupedignore` takes precedence over `.ignore`, which takes precedence over `.gitignore`.
+    ///
+    /// Ignored directories are not walked at all. Only ignore files inside the roots are taken into account.
+    pub fn respect_ignore_files(mut self, respect: bool) -> Self {
+        self.inner.walk.respect_ignore_files = respect;
+
+        self
+    }
+
+    /// Set the maximum depth of the walk.
+    ///
+    /// A depth of `1` only processes the files that are directly inside the roots, `2` also processes the files of
+    /// their subdirectories, and so on.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.inner.walk.max_depth = Some(depth);
+
+        self
+    }
+
+    /// Skip files and directories whose name starts with a `.`.
+    ///
+    /// The roots themselves are always walked, even if they are hidden.
+    pub fn skip_hidden(mut self, skip: bool) -> Self {
+        self.inner.walk.skip_hidden = skip;
+
+        self
+    }
+
+    /// Don't cross file system boundaries, i.e. only process files that are on the same file system as their root.
+    pub fn same_file_system(mut self, same: bool) -> Self {
+        self.inner.walk.same_file_system = same;
+
+        self
+    }
+
+    /// Set the number of threads that read directories while walking the roots.
+    ///
+    /// By default, one thread per CPU is used. Walks over network file systems, or over trees with many small files,
+    /// might benefit from more threads.
+    pub fn walk_threads(mut self, threads: usize) -> Self {
+        self.inner.walk.threads = threads.max(1);
+
+        self
+    }
+
+    /// Set the regions of each file that are hashed before the file is hashed sequentially.
+    ///
+    /// By default, [`ProbeSchedule::new`] is used. See [`ProbeSchedule`] for more details.
+    pub fn probe_schedule(mut self, schedule: ProbeSchedule) -> Self {
+        self.inner.hasher.probes = schedule;
+
+        self
+    }
+
+    /// Set how many bytes of each file are hashed sequentially in each round.
+    ///
+    /// By default, [`ChunkSchedule::default`] is used. See [`ChunkSchedule`] for more details.
+    pub fn chunk_schedule(mut self, schedule: ChunkSchedule) -> Self {
+        self.inner.hasher.chunks = schedule;
+
+        self
+    }
+
+    /// Set how files are read while they are hashed.
+    ///
+    /// By default, [`HashBackend::Std`] is used. See [`HashBackend`] for more details.
+    pub fn hash_backend(mut self, backend: HashBackend) -> Self {
+        self.inner.hasher.backend = backend;
+
+        self
+    }
+
+    /// Set the size from which sequential chunks are memory mapped and hashed by multiple threads.
+    ///
+    /// Chunks are only hashed in parallel while some hasher threads are idle, using their CPUs, so this mostly speeds up
+    /// the last rounds of a search, when a few large candidates are left. By default, chunks of at least 4 MiBs are
+    /// hashed in parallel. Chunks are only hashed in parallel with [`HashBackend::Std`].
+    #[cfg(feature = "parallel")]
+    pub fn parallel_hashing_threshold(mut self, bytes: u64) -> Self {
+        self.inner.hasher.parallel_threshold = bytes.max(1);
+
+        self
+    }
+
+    /// Set how the reads done while hashing interact with the page cache.
+    ///
+    /// By default, [`CacheMode::Cached`] is used, which lets a search evict the data of other processes from the page
+    /// cache. See [`CacheMode`] for the alternatives.
+    pub fn cache_mode(mut self, mode: CacheMode) -> Self {
+        self.inner.hasher.cache = mode;
+
+        self
+    }
+
+    /// Limit how many bytes are read, and how many files are opened, per second while hashing.
+    ///
+    /// Keep a clone of `throttle` to change the limits while a search is running. See [`Throttle`] for more details.
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.inner.hasher.throttle = throttle;
+
+        self
+    }
+
+    /// Use the idle I/O scheduling class for the threads that walk the roots and hash files, so that they only get
+    /// disk time when no other process needs it.
+    ///
+    /// This only has an effect on Linux, with I/O schedulers that support priorities (e.g. BFQ).
+    pub fn idle_io_priority(mut self, idle: bool) -> Self {
+        self.inner.walk.idle_io_priority = idle;
+        self.inner.hasher.idle_io_priority = idle;
+
+        self
+    }
+
+    /// Set the order in which the files of each round are read.
+    ///
+    /// By default, [`ReadOrder::Discovery`] is used. See [`ReadOrder`] for more details.
+    pub fn read_order(mut self, order: ReadOrder) -> Self {
+        self.inner.read_order = order;
+
+        self
+    }
+
+    /// Keep at most `max_in_memory` candidates of each round in memory, and spill the others to unnamed files in `dir`.
+    ///
+    /// This bounds the memory used by searches over tens of millions of files, at the cost of writing and reading back
+    /// about a hundred bytes per spilled candidate each round. Files that are hashed sequentially always stay in memory,
+    /// and so do all candidates when files are not read in [`ReadOrder::Discovery`] order, once their round starts.
+    pub fn spill_candidates(mut self, dir: PathBuf, max_in_memory: usize) -> Self {
+        self.inner.spill = Some(SpillConfig { dir, max_in_memory });
+
+        self
+    }
+
+    /// Add roots that are only searched for copies of the files found in the other roots, e.g. the roots of a backup.
+    ///
+    /// Files are not compared with the other files of their side: two files of the reference roots, or two files of the
+    /// other roots, are only hashed further if a file of the other side might have the same contents. See
+    /// [`DeduperResult::missing_from_reference`] to list the files that have no copy in the reference roots.
+    pub fn reference_roots(mut self, roots: Vec<PathBuf>) -> Self {
+        self.inner.first_reference_root.get_or_insert(self.inner.roots.len());
+        self.inner.roots.extend(roots);
+
+        self
+    }
+
+    /// Hash every file entirely, even those that can't have a duplicate (or a copy in the reference roots).
+    ///
+    /// This makes the search read every selected file from start to end, but [`DeduperResult::hashes`] then holds the
+    /// hash of the entire contents of every file, which is needed to save a [`Manifest`]. Since no file is set aside,
+    /// the files aren't probed (see [`Self::probe_schedule`]) before being hashed.
+    pub fn hash_all_files(mut self, hash_all: bool) -> Self {
+        self.inner.hash_all_files = hash_all;
+
+        self
+    }
+
     /// Build a [`Deduper`].
     pub fn build(self) -> Deduper {
         Deduper { inner: self.inner }
     }
 }
 
+/// A message sent to the collector thread.
+enum Collected {
+    /// A hasher finished a round of hashing.
+    Hashed(Box<ProgressiveHasher>, io::Result<()>),
+    /// A file whose size is unique, so it doesn't need to be hashed.
+    Unique(FilePath),
+    /// All the hashers of the current round were sent to the hasher threads (at the end of the walk for the first
+    /// round), and this is how many there are.
+    Dispatched(usize),
+}
+
+/// The state shared by the hasher threads.
+struct HasherShared {
+    config: HasherConfig,
+    /// The CPUs of the hasher threads, which idle threads lend to threads that hash large chunks.
+    #[cfg(feature = "parallel")]
+    cpus: parallel::Cpus,
+}
+
 fn hasher_task(
-    worker_id: usize,
     tasks: Receiver<Vec<ProgressiveHasher>>,
-    tx: SyncSender<(usize, ProgressiveHasher, io::Result<()>)>,
+    tx: SyncSender<Collected>,
+    shared: Arc<HasherShared>,
 ) {
-    while let Ok(hashers) = tasks.recv() {
+    let config = &shared.config;
+    if config.idle_io_priority {
+        if let Err(e) = throttle::set_idle_io_priority() {
+            warn!(error = %e, "failed to set the I/O priority of a hasher thread");
+        }
+    }
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if let HashBackend::IoUring { queue_depth } = config.backend {
+        match uring::UringReader::new(queue_depth) {
+            Ok(mut reader) => {
+                while let Ok(hashers) = tasks.recv() {
+                    let mut sent = true;
+                    let res = reader.hash(hashers, config, |hasher, res| {
+                        sent = tx.send(Collected::Hashed(Box::new(hasher), res)).is_ok();
+                        sent
+                    });
+                    if !sent {
+                        error!("failed to send hash, quiting...");
+                        return;
+                    }
+                    if let Err(e) = res {
+                        error!(error = %e, "io_uring failed, falling back to blocking reads");
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to set up io_uring, falling back to blocking reads")
+            }
+        }
+    }
+
+    loop {
+        #[cfg(feature = "parallel")]
+        shared.cpus.lend();
+        let Ok(hashers) = tasks.recv() else {
+            break;
+        };
+        #[cfg(feature = "parallel")]
+        shared.cpus.reclaim();
+
         for mut hasher in hashers {
-            let res = hasher.update();
+            #[cfg(feature = "parallel")]
+            let res = parallel::update(&mut hasher, config, &shared.cpus);
+            #[cfg(not(feature = "parallel"))]
+            let res = hasher.update(config);
 
-            if tx.send((worker_id, hasher, res)).is_err() {
+            if tx.send(Collected::Hashed(Box::new(hasher), res)).is_err() {
                 error!("failed to send hash, quiting...");
                 break;
             }
@@ -225,21 +754,55 @@ fn hasher_task(
 }
 
 fn collect(
-    mut responses: usize,
-    rx: Receiver<(usize, ProgressiveHasher, io::Result<()>)>,
-    rehash_files_tx: SyncSender<Vec<ProgressiveHasher>>,
+    rx: Receiver<Collected>,
+    rehash_files_tx: SyncSender<CandidateList>,
     hooks: Arc<dyn DeduperFindHook>,
+    spill: Option<SpillConfig>,
+    elimination: Elimination,
 ) -> DeduperResult {
     let mut duplicates = DeduperResult::default();
-
-    while responses > 0 {
-        let mut hasher_set = hasher::HasherSet::default();
-        for _ in 0..responses {
-            let Ok((_, hasher, res)) = rx.recv() else {
+    // the hashes of the files that were hashed entirely, to tell how many of them have no duplicates in the end
+    let mut full_hashes = HashSet::new();
+
+    for round in 1.. {
+        let round_start = Instant::now();
+        let mut round_stats = RoundStats { duration: Duration::ZERO, candidates: 0, bytes_read: 0 };
+        // the number of hashers of a round is only known once they were all dispatched
+        let mut responses = None;
+        let mut hasher_set = hasher::HasherSet::new(spill.clone(), elimination);
+        let mut received = 0;
+        let (mut eliminated, mut bytes_saved) = (0, 0);
+        while responses.is_none_or(|responses| received < responses) {
+            let Ok(collected) = rx.recv() else {
                 break;
             };
 
+            let (hasher, res) = match collected {
+                Collected::Hashed(hasher, res) => (hasher, res),
+                Collected::Unique(file_path) => {
+                    let hasher = ProgressiveHasher::new(file_path);
+                    let (hash, _) = hasher.current_hash();
+                    let entry = hasher.file_path().to_file_entry();
+                    eliminated += 1;
+                    bytes_saved += entry.size();
+                    duplicates.stats_mut().eliminated_by_size += 1;
+                    hooks.entry_processed(hash, &entry);
+                    duplicates.add_entry(hash, entry);
+                    continue;
+                }
+                Collected::Dispatched(dispatched) => {
+                    responses = Some(dispatched);
+                    continue;
+                }
+            };
+            received += 1;
+            round_stats.candidates += 1;
+            round_stats.bytes_read += hasher.round_bytes_read();
+            hooks.bytes_hashed(hasher.round_bytes());
+
             let (hash, done) = hasher.current_hash();
+            // files that couldn't be opened or read count too, since an attempt was made to read them
+            duplicates.stats_mut().files_opened += 1;
 
             if let Err(e) = res {
                 error!(
@@ -247,29 +810,50 @@ fn collect(
                     path = %hasher.file_path().path().display(),
                     "failed to process file"
                 );
+                let path = hasher.file_path().path();
+                duplicates.add_skipped([SkippedEntry::new(path, SkipReason::Io(e.kind()))]);
                 continue;
-            } else if done {
+            }
+
+            if done {
+                full_hashes.insert(hash);
                 let entry = hasher.file_path().to_file_entry();
                 hooks.entry_processed(hash, &entry);
                 duplicates.add_entry(hash, entry);
             } else {
-                hasher_set.insert(hasher);
+                hasher_set.insert(*hasher);
             }
         }
 
-        let (finished, hashers) = hasher_set.filter_unfinished_duplicates();
-        for hasher in finished {
+        let hashers = hasher_set.filter_unfinished_duplicates(|hasher| {
             let (hash, _) = hasher.current_hash();
             let entry = hasher.file_path().to_file_entry();
+            eliminated += 1;
+            bytes_saved += hasher.bytes_left();
+            duplicates.stats_mut().eliminated_by_partial_hash += 1;
             hooks.entry_processed(hash, &entry);
             duplicates.add_entry(hash, entry);
+        });
+        if eliminated > 0 {
+            hooks.candidates_eliminated(eliminated, bytes_saved);
         }
-        responses = hashers.len();
+        round_stats.duration = round_start.elapsed();
+        duplicates.stats_mut().rounds.push(round_stats);
+        hooks.round_finished(round, eliminated, hashers.len());
+        let done = hashers.is_empty();
         if rehash_files_tx.send(hashers).is_err() {
             error!("rehash_files channel is closed");
             break;
         }
+        if done {
+            break;
+        }
     }
 
+    let unique =
+        full_hashes.iter().filter(|hash| !duplicates.hashes()[*hash].has_duplicates()).count();
+    duplicates.stats_mut().eliminated_by_full_hash = unique;
+    duplicates.sort_entries();
+
     duplicates
 }
@@ -0,0 +1,460 @@
+//! Saves the hash of every file found by a search, so that two searches of the same tree can be compared later on.
+//!
+//! A manifest is a text file, whose first line is [`HEADER`]. It is followed by a `lower-limit\t<bytes>` line, a
+//! `skipped\t<count>` line, a `root\t<path>` line per root, and a `<hash>\t<size>\t<root index>\t<path>` line per file,
+//! where the path is relative to the root the file was found in. Backslashes, tabs, and line breaks in paths are
+//! escaped, and so are the bytes that aren't valid UTF-8.
+
+use crate::DeduperResult;
+
+use blake3::Hash;
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// The first line of a manifest, which also tells the version of the format.
+const HEADER: &str = "duped manifest 1";
+
+/// A file of a [`Manifest`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    root: usize,
+    path: PathBuf,
+    size: u64,
+    hash: Hash,
+}
+
+impl ManifestEntry {
+    /// Get the index of the root the file was found in, see [`Manifest::roots`].
+    pub fn root_index(&self) -> usize {
+        self.root
+    }
+
+    /// Get the path of the file, relative to its root.
+    pub fn relative_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the size of the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the hash of the entire contents of the file.
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+}
+
+/// The hash of every file found by a search, which can be saved to disk, and compared with the manifest of a later
+/// search of the same roots with [`Self::diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// The size under which files weren't searched.
+    lower_limit: u64,
+    /// How many files were skipped, see [`DeduperResult::skipped`].
+    skipped: usize,
+    roots: Vec<PathBuf>,
+    /// The files, sorted by root and path.
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Create the manifest of a search of `roots` (see [`crate::Deduper::roots`]), which returned `result`.
+    ///
+    /// Return an error of kind [`io::ErrorKind::InvalidInput`] if `result` doesn't hold the hash of the entire contents
+    /// of every file (see [`crate::DeduperBuilder::hash_all_files`]), if the search was stopped before the end, or if
+    /// `roots` aren't the roots that were searched. Files that couldn't be read (see [`DeduperResult::skipped`]) are
+    /// not part of the manifest, but they are counted (see [`Self::skipped`]).
+    pub fn new(roots: &[PathBuf], result: &DeduperResult) -> io::Result<Self> {
+        let invalid = |error: String| io::Error::new(io::ErrorKind::InvalidInput, error);
+        if !result.is_fully_hashed() {
+            return Err(invalid("the files weren't all hashed entirely".to_owned()));
+        }
+        if result.is_partial() {
+            return Err(invalid("the search was stopped before the end".to_owned()));
+        }
+
+        let mut entries = result
+            .hashes()
+            .iter()
+            .flat_map(|(hash, entries)| entries.entries().map(move |entry| (hash, entry)))
+            .map(|(hash, entry)| {
+                let root = entry.root_index();
+                let path = entry.path();
+                let relative = roots.get(root).and_then(|root| path.strip_prefix(root).ok());
+                let Some(relative) = relative else {
+                    return Err(invalid(format!("'{}' isn't under its root", path.display())));
+                };
+                let path = relative.to_path_buf();
+                Ok(ManifestEntry { root, path, size: entry.size(), hash: *hash })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by(|e1, e2| (e1.root, &e1.path).cmp(&(e2.root, &e2.path)));
+
+        Ok(Self { lower_limit: 0, skipped: result.skipped().len(), roots: roots.to_vec(), entries })
+    }
+
+    /// Record that files under `lower_limit` bytes weren't searched, and so aren't part of the manifest.
+    pub fn with_lower_limit(mut self, lower_limit: u64) -> Self {
+        self.lower_limit = lower_limit;
+
+        self
+    }
+
+    /// Get the size under which files weren't searched, see [`Self::with_lower_limit`].
+    pub fn lower_limit(&self) -> u64 {
+        self.lower_limit
+    }
+
+    /// Get how many files were skipped by the search, and so aren't part of the manifest.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Get the roots that were searched.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Get the files that were found, sorted by root and path.
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+
+    /// Get the path of `entry`, i.e. its relative path joined to its root.
+    pub fn path(&self, entry: &ManifestEntry) -> PathBuf {
+        self.roots.get(entry.root).map_or_else(|| entry.path.clone(), |root| root.join(&entry.path))
+    }
+
+    /// Write the manifest to `out`, in a format that [`Self::read_from`] reads back.
+    pub fn write_to(&self, out: impl Write) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        writeln!(out, "{HEADER}")?;
+        writeln!(out, "lower-limit\t{}", self.lower_limit)?;
+        writeln!(out, "skipped\t{}", self.skipped)?;
+        for root in &self.roots {
+            writeln!(out, "root\t{}", escape(root))?;
+        }
+        for entry in &self.entries {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}",
+                entry.hash,
+                entry.size,
+                entry.root,
+                escape(&entry.path)
+            )?;
+        }
+
+        out.flush()
+    }
+
+    /// Read a manifest that was written with [`Self::write_to`].
+    pub fn read_from(input: impl BufRead) -> io::Result<Self> {
+        let mut lines = input.lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a duped manifest"));
+        }
+
+        let mut manifest = Self::default();
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            // the header is the first line
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: invalid entry", number + 2),
+                )
+            };
+            if let Some(root) = line.strip_prefix("root\t") {
+                manifest.roots.push(unescape(root).ok_or_else(invalid)?);
+                continue;
+            }
+            if let Some(lower_limit) = line.strip_prefix("lower-limit\t") {
+                manifest.lower_limit = lower_limit.parse().map_err(|_| invalid())?;
+                continue;
+            }
+            if let Some(skipped) = line.strip_prefix("skipped\t") {
+                manifest.skipped = skipped.parse().map_err(|_| invalid())?;
+                continue;
+            }
+
+            let mut fields = line.splitn(4, '\t');
+            let mut field = || fields.next().ok_or_else(invalid);
+            let hash = Hash::from_hex(field()?).map_err(|_| invalid())?;
+            let size = field()?.parse().map_err(|_| invalid())?;
+            let root = field()?.parse().map_err(|_| invalid())?;
+            let path = unescape(field()?).ok_or_else(invalid)?;
+            if root >= manifest.roots.len() {
+                return Err(invalid());
+            }
+            manifest.entries.push(ManifestEntry { root, path, size, hash });
+        }
+        manifest.entries.sort_by(|e1, e2| (e1.root, &e1.path).cmp(&(e2.root, &e2.path)));
+
+        Ok(manifest)
+    }
+
+    /// Compare this manifest with the manifest of a later search of the same roots.
+    ///
+    /// Files are told apart by their root index and their path relative to it, so the roots can be mounted elsewhere
+    /// between the two searches. A file that was removed is reported as moved if a file with the same contents was
+    /// added. If several files with the same contents were moved, a removed file is preferably paired with an added
+    /// file that has the same name.
+    pub fn diff(&self, newer: &Manifest) -> ManifestDiff {
+        let key = |entry: &ManifestEntry| (entry.root, entry.path.clone());
+        let old: HashMap<_, _> = self.entries.iter().map(|entry| (key(entry), entry)).collect();
+        let new: HashMap<_, _> = newer.entries.iter().map(|entry| (key(entry), entry)).collect();
+
+        let mut diff = ManifestDiff::default();
+        // the files that were removed, by hash
+        let mut removed: HashMap<Hash, Vec<&ManifestEntry>> = HashMap::new();
+        for entry in &self.entries {
+            match new.get(&key(entry)) {
+                Some(new) if new.hash != entry.hash => {
+                    diff.modified.push((entry.clone(), (*new).clone()))
+                }
+                Some(_) => {}
+                None => removed.entry(entry.hash).or_default().push(entry),
+            }
+        }
+        for entry in newer.entries.iter().filter(|entry| !old.contains_key(&key(entry))) {
+            let Some(candidates) = removed.get_mut(&entry.hash).filter(|c| !c.is_empty()) else {
+                diff.added.push(entry.clone());
+                continue;
+            };
+            let same_name =
+                candidates.iter().position(|c| c.path.file_name() == entry.path.file_name());
+            let moved = candidates.remove(same_name.unwrap_or(0));
+            diff.moved.push((moved.clone(), entry.clone()));
+        }
+        diff.removed = removed.into_values().flatten().cloned().collect();
+        diff.removed.sort_by(|e1, e2| (e1.root, &e1.path).cmp(&(e2.root, &e2.path)));
+
+        diff
+    }
+}
+
+/// The changes between two manifests, see [`Manifest::diff`].
+///
+/// All lists are sorted by root and path (of the newer file, if there are two).
+#[derive(Clone, Debug, Default)]
+pub struct ManifestDiff {
+    added: Vec<ManifestEntry>,
+    removed: Vec<ManifestEntry>,
+    modified: Vec<(ManifestEntry, ManifestEntry)>,
+    moved: Vec<(ManifestEntry, ManifestEntry)>,
+}
+
+impl ManifestDiff {
+    /// Get the files that were added, and don't have the contents of a removed file.
+    pub fn added(&self) -> &[ManifestEntry] {
+        &self.added
+    }
+
+    /// Get the files that were removed, and whose contents weren't found in an added file.
+    pub fn removed(&self) -> &[ManifestEntry] {
+        &self.removed
+    }
+
+    /// Get the files whose contents changed, as they were before and after.
+    pub fn modified(&self) -> &[(ManifestEntry, ManifestEntry)] {
+        &self.modified
+    }
+
+    /// Get the files that were moved or renamed, as they were before and after.
+    pub fn moved(&self) -> &[(ManifestEntry, ManifestEntry)] {
+        &self.moved
+    }
+
+    /// Return `true` if nothing changed between the two manifests.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+/// Escape `path` so that it fits on a line of a manifest.
+fn escape(path: &Path) -> String {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+
+        path.as_os_str().as_bytes()
+    };
+    #[cfg(not(unix))]
+    let lossy = path.to_string_lossy();
+    #[cfg(not(unix))]
+    let bytes = lossy.as_bytes();
+
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    escaped
+}
+
+/// Reverse [`escape`], or return `None` if `escaped` isn't a valid escaped path.
+fn unescape(escaped: &str) -> Option<PathBuf> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+        match chars.next()? {
+            '\\' => bytes.push(b'\\'),
+            't' => bytes.push(b'\t'),
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+
+        Some(std::ffi::OsString::from_vec(bytes).into())
+    }
+    #[cfg(not(unix))]
+    {
+        String::from_utf8(bytes).ok().map(PathBuf::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, contents: &[u8]) -> ManifestEntry {
+        let hash = blake3::hash(contents);
+        ManifestEntry { root: 0, path: path.into(), size: contents.len() as u64, hash }
+    }
+
+    fn search(root: &Path) -> DeduperResult {
+        let deduper = crate::Deduper::builder(vec![root.to_owned()]).hash_all_files(true).build();
+        deduper.find(crate::ContentLimit::no_limit(), crate::NoopFindHook).unwrap()
+    }
+
+    #[test]
+    fn manifests_need_the_searched_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), b"a").unwrap();
+        let result = search(dir.path());
+
+        for roots in [vec![], vec!["/elsewhere".into()]] {
+            let error = Manifest::new(&roots, &result).unwrap_err();
+            assert!(error.to_string().ends_with("isn't under its root"), "{error}");
+        }
+        let manifest = Manifest::new(&[dir.path().to_owned()], &result).unwrap();
+        assert_eq!(manifest.entries(), [entry("a", b"a")]);
+    }
+
+    #[test]
+    fn files_are_not_probed_when_all_are_hashed() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.path().join("a"), &data).unwrap();
+        let result = search(dir.path());
+
+        assert_eq!(result.stats().bytes_read(), data.len() as u64);
+        let manifest = Manifest::new(&[dir.path().to_owned()], &result).unwrap();
+        assert_eq!(manifest.entries(), [entry("a", &data)]);
+    }
+
+    #[test]
+    fn manifests_are_read_back() {
+        #[cfg(unix)]
+        let odd = {
+            use std::os::unix::ffi::OsStrExt;
+
+            Path::new(std::ffi::OsStr::from_bytes(b"tab\there\\new\nline\xff")).to_path_buf()
+        };
+        #[cfg(not(unix))]
+        let odd = PathBuf::from("tab\there\\new\nline");
+        let manifest = Manifest {
+            lower_limit: 1024,
+            skipped: 3,
+            roots: vec!["/some root".into()],
+            entries: vec![entry("a", b"a"), ManifestEntry { path: odd, ..entry("", b"odd") }],
+        };
+
+        let mut written = vec![];
+        manifest.write_to(&mut written).unwrap();
+        assert_eq!(written.iter().filter(|&&b| b == b'\n').count(), 6);
+        let read = Manifest::read_from(written.as_slice()).unwrap();
+        let mut sorted = manifest.clone();
+        sorted.entries.sort_by(|e1, e2| e1.path.cmp(&e2.path));
+        assert_eq!(read, sorted);
+
+        assert!(Manifest::read_from(&b"not a manifest\n"[..]).is_err());
+        let mut bad = written.clone();
+        bad.extend_from_slice(b"nope\t1\t0\tpath\n");
+        let e = Manifest::read_from(bad.as_slice()).unwrap_err();
+        assert_eq!(e.to_string(), "line 7: invalid entry");
+    }
+
+    #[test]
+    fn moves_are_told_apart_from_additions() {
+        let old = Manifest {
+            roots: vec!["/old".into()],
+            entries: vec![
+                entry("a", b"same"),
+                entry("b", b"before"),
+                entry("c/copy", b"copied"),
+                entry("c/x", b"moved"),
+                entry("d/copy", b"copied"),
+                entry("gone", b"gone"),
+            ],
+            ..Default::default()
+        };
+        let new = Manifest {
+            roots: vec!["/new".into()],
+            entries: vec![
+                entry("a", b"same"),
+                entry("b", b"after"),
+                entry("e/copy", b"copied"),
+                entry("e/y", b"moved"),
+                entry("new", b"new"),
+            ],
+            ..Default::default()
+        };
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.added(), [entry("new", b"new")]);
+        assert_eq!(diff.removed(), [entry("d/copy", b"copied"), entry("gone", b"gone")]);
+        assert_eq!(diff.modified(), [(entry("b", b"before"), entry("b", b"after"))]);
+        assert_eq!(
+            diff.moved(),
+            [
+                (entry("c/copy", b"copied"), entry("e/copy", b"copied")),
+                (entry("c/x", b"moved"), entry("e/y", b"moved"))
+            ]
+        );
+        assert!(!diff.is_empty());
+        assert!(new.diff(&new).is_empty());
+    }
+}
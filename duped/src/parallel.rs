@@ -0,0 +1,187 @@
+//! Hashes large chunks of a file with multiple threads.
+//!
+//! Towards the end of a search, only a few large candidates might be left, e.g. two disk images of hundreds of GiBs,
+//! which leaves most hasher threads idle. Idle hasher threads lend their CPU to [`Cpus`], and a thread that is about to
+//! hash a large chunk borrows the CPUs that are available, memory maps the chunk, and hashes it with a rayon pool that
+//! is no larger than the number of CPUs it holds. This way, the number of threads that are hashing never exceeds the
+//! number of hasher threads.
+
+use crate::{
+    cache::{self, CacheMode},
+    hasher::{open_regular_file, HasherConfig, ProgressiveHasher},
+};
+
+use memmap2::MmapOptions;
+use rayon_core::{ThreadPool, ThreadPoolBuilder};
+use tracing::warn;
+
+use std::{
+    io,
+    sync::{Condvar, Mutex, OnceLock},
+};
+
+/// The CPUs of the hasher threads, which can be borrowed while their threads are idle.
+pub(crate) struct Cpus {
+    /// How many CPUs are not used by any thread.
+    idle: Mutex<usize>,
+    returned: Condvar,
+    /// Pools of 2, 4, 8, ... threads, created on demand.
+    pools: Vec<OnceLock<Option<ThreadPool>>>,
+}
+
+impl Cpus {
+    /// Create a new instance for `threads` hasher threads, which are all busy.
+    pub(crate) fn new(threads: usize) -> Self {
+        let pools = (1..=threads.max(1).ilog2()).map(|_| OnceLock::new()).collect();
+        Self { idle: Mutex::new(0), returned: Condvar::new(), pools }
+    }
+
+    /// Lend the CPU of the calling thread, which is about to wait for work.
+    pub(crate) fn lend(&self) {
+        self.give_back(1);
+    }
+
+    /// Take the CPU of the calling thread back, waiting until another thread gives it back if it was borrowed.
+    pub(crate) fn reclaim(&self) {
+        let mut idle = self.idle.lock().expect("poisoned lock");
+        while *idle == 0 {
+            idle = self.returned.wait(idle).expect("poisoned lock");
+        }
+        *idle -= 1;
+    }
+
+    /// Borrow the idle CPUs, and return a pool that has as many threads as the CPUs that were borrowed, plus the CPU of
+    /// the calling thread, along with the number of borrowed CPUs.
+    fn borrow(&self) -> Option<(&ThreadPool, usize)> {
+        let mut idle = self.idle.lock().expect("poisoned lock");
+        let available = (*idle + 1).min(1 << self.pools.len());
+        if available < 2 {
+            return None;
+        }
+        let log = available.ilog2();
+        let pool = self.pools[log as usize - 1]
+            .get_or_init(|| match ThreadPoolBuilder::new().num_threads(1 << log).build() {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    warn!(error = %e, "failed to create a thread pool, hashing on a single thread");
+                    None
+                }
+            })
+            .as_ref()?;
+        let borrowed = (1 << log) - 1;
+        *idle -= borrowed;
+
+        Some((pool, borrowed))
+    }
+
+    fn give_back(&self, cpus: usize) {
+        *self.idle.lock().expect("poisoned lock") += cpus;
+        self.returned.notify_all();
+    }
+}
+
+/// Hashes the next probe of the file, or the next chunk of the file if probing is over, just like
+/// [`ProgressiveHasher::update`].
+///
+/// Chunks of at least [`HasherConfig::parallel_threshold`] bytes are memory mapped and hashed in parallel, if other
+/// hasher threads are idle, and if direct reads weren't requested.
+pub(crate) fn update(
+    hasher: &mut ProgressiveHasher,
+    config: &HasherConfig,
+    cpus: &Cpus,
+) -> io::Result<()> {
+    // direct reads can't be memory mapped
+    if config.cache == CacheMode::Direct {
+        return hasher.update(config);
+    }
+
+    let mut file = open_regular_file(&hasher.file_path().path())?;
+    let round = hasher.next_round(config);
+    config.throttle.acquire(round.len(), 1);
+    let &[(offset, len)] = round.regions() else {
+        return hasher.read_round(&mut file, &round, config.cache);
+    };
+    if !round.is_sequential() || len < config.parallel_threshold {
+        return hasher.read_round(&mut file, &round, config.cache);
+    }
+    // the file might have shrunk since it was found, and reading a mapping past the end of the file raises SIGBUS, so
+    // such files are read instead, which fails the round like any other read past the end of the file
+    if offset + len > file.metadata()?.len() {
+        return hasher.read_round(&mut file, &round, config.cache);
+    }
+    let Some((pool, borrowed)) = cpus.borrow() else {
+        return hasher.read_round(&mut file, &round, config.cache);
+    };
+
+    // SAFETY: the mapping is only read while hashing, but its content is undefined if the file is modified in the
+    // meantime (and reading past the end of the file raises SIGBUS if it is truncated after the check above), the same
+    // way `blake3::Hasher::update_mmap` behaves.
+    let map = unsafe { MmapOptions::new().offset(offset).len(len as usize).map(&file) };
+    if let Ok(map) = &map {
+        pool.install(|| hasher.feed_parallel(&round, map));
+    }
+    cpus.give_back(borrowed);
+    if map.is_ok() && config.cache != CacheMode::Cached {
+        cache::drop_from_cache(&file, offset, len);
+    }
+
+    match map {
+        Ok(_) => Ok(()),
+        Err(_) => hasher.read_round(&mut file, &round, config.cache),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hasher::testing::hasher, ChunkSchedule, ProbeSchedule};
+
+    #[test]
+    fn same_hash_as_sequential_hashing() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..5 * 1024 * 1024 + 7).map(|i: u32| (i % 253) as u8).collect();
+        let mut hasher = hasher(dir.path(), "a", &data);
+        let config = HasherConfig {
+            probes: ProbeSchedule::disabled(),
+            chunks: ChunkSchedule::fixed(2 * 1024 * 1024),
+            parallel_threshold: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let cpus = Cpus::new(4);
+        for _ in 0..3 {
+            cpus.lend();
+        }
+        loop {
+            update(&mut hasher, &config, &cpus).unwrap();
+            assert_eq!(*cpus.idle.lock().unwrap(), 3);
+            if let (hash, true) = hasher.current_hash() {
+                assert_eq!(hash, blake3::hash(&data));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_not_mapped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut hasher = hasher(dir.path(), "a", &vec![1; 4 * 1024 * 1024]);
+        let config = HasherConfig {
+            probes: ProbeSchedule::disabled(),
+            chunks: ChunkSchedule::fixed(4 * 1024 * 1024),
+            parallel_threshold: 1024 * 1024,
+            ..Default::default()
+        };
+        // the file shrinks between the walk and hashing
+        let file = std::fs::File::options().write(true).open(dir.path().join("a")).unwrap();
+        file.set_len(1024 * 1024).unwrap();
+
+        let cpus = Cpus::new(4);
+        for _ in 0..3 {
+            cpus.lend();
+        }
+        let e = update(&mut hasher, &config, &cpus).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(*cpus.idle.lock().unwrap(), 3);
+    }
+}
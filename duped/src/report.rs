@@ -0,0 +1,436 @@
+//! Reports that aggregate the duplicates of a [`DeduperResult`], to help decide where to clean up.
+
+use crate::DeduperResult;
+
+use blake3::Hash;
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Groups of duplicates spread over more directories than this are ignored when counting the content shared by pairs
+/// of directories, since the number of pairs grows quadratically (think of license files).
+const MAX_DIRS_PER_GROUP_FOR_PAIRS: usize = 64;
+
+/// Duplicated bytes attributed to directories, see [`DeduperResult::directory_report`].
+#[derive(Debug)]
+pub struct DirectoryReport {
+    directories: Vec<DirectoryWaste>,
+    pairs: Vec<DirectoryPair>,
+}
+
+impl DirectoryReport {
+    /// The directories that hold duplicates, the ones holding the most redundant data first.
+    pub fn directories(&self) -> &[DirectoryWaste] {
+        &self.directories
+    }
+
+    /// The pairs of directories that share content, the ones sharing the most content first.
+    pub fn pairs(&self) -> &[DirectoryPair] {
+        &self.pairs
+    }
+}
+
+/// The redundant data held by a directory and its subdirectories.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryWaste {
+    path: PathBuf,
+    wasted_bytes: u64,
+    redundant_files: usize,
+}
+
+impl DirectoryWaste {
+    /// The path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How many bytes would be freed by removing the redundant copies found under the directory.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes
+    }
+
+    /// How many redundant copies were found under the directory.
+    pub fn redundant_files(&self) -> usize {
+        self.redundant_files
+    }
+}
+
+/// Two directories that directly contain copies of the same files.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectoryPair {
+    first: PathBuf,
+    second: PathBuf,
+    shared_bytes: u64,
+    shared_files: usize,
+}
+
+impl DirectoryPair {
+    /// The directories of the pair, in lexicographic order.
+    pub fn paths(&self) -> (&Path, &Path) {
+        (&self.first, &self.second)
+    }
+
+    /// The total size of the files that both directories contain.
+    pub fn shared_bytes(&self) -> u64 {
+        self.shared_bytes
+    }
+
+    /// How many distinct files both directories contain.
+    pub fn shared_files(&self) -> usize {
+        self.shared_files
+    }
+}
+
+/// Directories whose trees are identical: same names, same contents, all the way down.
+#[derive(Clone, Debug)]
+pub struct DuplicateTree {
+    hash: Hash,
+    paths: Vec<PathBuf>,
+    size: u64,
+    files: usize,
+}
+
+impl DuplicateTree {
+    /// The hash of the tree, computed from the names and hashes of its files and subdirectories.
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// The copies of the tree, sorted.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// The total size of the files of one copy of the tree.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The number of files in one copy of the tree.
+    pub fn files(&self) -> usize {
+        self.files
+    }
+
+    /// How many bytes would be freed by keeping only one copy of the tree.
+    pub fn wasted_bytes(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1)
+    }
+
+    /// Return `true` if `path` is inside one of the copies of the tree.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.iter().any(|tree| path.starts_with(tree))
+    }
+}
+
+/// Two directories that directly contain many of the same files, see [`DeduperResult::similar_directories`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarDirectories {
+    first: PathBuf,
+    second: PathBuf,
+    shared: usize,
+    first_only: usize,
+    second_only: usize,
+}
+
+impl SimilarDirectories {
+    /// The directories of the pair, in lexicographic order.
+    pub fn paths(&self) -> (&Path, &Path) {
+        (&self.first, &self.second)
+    }
+
+    /// How many distinct files both directories contain.
+    pub fn shared(&self) -> usize {
+        self.shared
+    }
+
+    /// How many distinct files only the first directory contains.
+    pub fn first_only(&self) -> usize {
+        self.first_only
+    }
+
+    /// How many distinct files only the second directory contains.
+    pub fn second_only(&self) -> usize {
+        self.second_only
+    }
+
+    /// The Jaccard index of the contents of the directories, between `0.0` and `1.0`: the number of files both
+    /// directories contain, over the number of files any of them contains.
+    pub fn similarity(&self) -> f64 {
+        self.shared as f64 / (self.shared + self.first_only + self.second_only) as f64
+    }
+}
+
+/// What is known about a directory while computing the hashes of trees.
+#[derive(Default)]
+struct DirNode {
+    /// The name and hash of each file and subdirectory, tagged to tell them apart.
+    children: Vec<(u8, OsString, [u8; 32])>,
+    size: u64,
+    files: usize,
+    /// The root the files of the tree were found in.
+    root: Option<usize>,
+    /// Whether an entry of the tree was skipped, in which case the tree can't be compared to others.
+    incomplete: bool,
+}
+
+impl DeduperResult {
+    /// Find the pairs of directories whose files are at least `min_similarity` similar, see
+    /// [`SimilarDirectories::similarity`].
+    ///
+    /// Files are compared by content, regardless of their names, and only the files directly inside each directory
+    /// are compared. The most similar pairs come first.
+    ///
+    /// Pairs of directories are found through the duplicates they share, leaving out the groups of duplicates that are
+    /// spread over too many directories (think of license files). So two directories whose only shared files are
+    /// copied all over the place are not listed, but the files they share are always counted for the pairs that are.
+    pub fn similar_directories(&self, min_similarity: f64) -> Vec<SimilarDirectories> {
+        // the distinct contents of each directory
+        let mut contents: HashMap<&Path, HashSet<&Hash>> = HashMap::new();
+        let paths: Vec<(&Hash, PathBuf)> = self
+            .hashes()
+            .iter()
+            .flat_map(|(hash, entries)| entries.entries().map(move |e| (hash, e.path())))
+            .collect();
+        for (hash, path) in &paths {
+            if let Some(parent) = path.parent() {
+                contents.entry(parent).or_default().insert(hash);
+            }
+        }
+
+        let mut pairs: HashSet<(&Path, &Path)> = HashSet::new();
+        for (_, entries) in self.duplicates() {
+            let dirs: BTreeSet<_> =
+                entries.iter().filter_map(|path| path.parent().map(Path::to_owned)).collect();
+            if dirs.len() > MAX_DIRS_PER_GROUP_FOR_PAIRS {
+                continue;
+            }
+            // borrow the directories from `contents`, which outlives the pairs
+            let dirs: Vec<&Path> = dirs
+                .iter()
+                .filter_map(|dir| contents.get_key_value(dir.as_path()).map(|(k, _)| *k))
+                .collect();
+            for (i, first) in dirs.iter().enumerate() {
+                for second in &dirs[i + 1..] {
+                    pairs.insert((*first, *second));
+                }
+            }
+        }
+
+        let mut similar: Vec<_> = pairs
+            .into_iter()
+            .map(|(first, second)| {
+                let (first_contents, second_contents) = (&contents[first], &contents[second]);
+                let shared = first_contents.intersection(second_contents).count();
+                SimilarDirectories {
+                    first: first.to_owned(),
+                    second: second.to_owned(),
+                    shared,
+                    first_only: first_contents.len() - shared,
+                    second_only: second_contents.len() - shared,
+                }
+            })
+            .filter(|pair| pair.similarity() >= min_similarity)
+            .collect();
+        similar.sort_by(|a, b| {
+            b.similarity()
+                .total_cmp(&a.similarity())
+                .then_with(|| b.shared.cmp(&a.shared))
+                .then_with(|| a.paths().cmp(&b.paths()))
+        });
+
+        similar
+    }
+
+    /// Find the directories whose trees are identical.
+    ///
+    /// The hash of a directory is computed from the names and hashes of its children (a Merkle tree), so empty
+    /// directories are ignored. Trees that contain skipped files, or files and directories that the filter or the walk
+    /// options (such as [`crate::DeduperBuilder::max_depth`]) excluded, are never reported, since their copies might
+    /// differ in those files. Neither are trees whose copies don't pass the [`crate::RootFilter`] of the result.
+    ///
+    /// Copies of a tree are only reported once: the subdirectories of identical trees are identical as well, so they
+    /// are left out, unless some of their copies are elsewhere. The trees that waste the most space come first.
+    pub fn duplicate_trees(&self) -> Vec<DuplicateTree> {
+        let mut dirs: HashMap<PathBuf, DirNode> = HashMap::new();
+        for (hash, entries) in self.hashes() {
+            for entry in entries.entries() {
+                let path = entry.path();
+                let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+                    continue;
+                };
+                let dir = dirs.entry(parent.to_owned()).or_default();
+                dir.children.push((b'f', name.to_owned(), *hash.as_bytes()));
+                dir.size += entry.size();
+                dir.files += 1;
+                dir.root = Some(entry.root_index());
+            }
+        }
+        for skipped in self.skipped() {
+            if let Some(parent) = skipped.path().parent() {
+                dirs.entry(parent.to_owned()).or_default().incomplete = true;
+            }
+        }
+        // the files that were filtered out might differ from one copy to the next
+        for dir in self.filtered_dirs() {
+            dirs.entry(dir.clone()).or_default().incomplete = true;
+        }
+
+        // make sure that all the ancestors are known, up to the deepest one that contains everything
+        let top = dirs.keys().cloned().reduce(|common, dir| common_ancestor(&common, &dir));
+        let Some(top) = top else {
+            return vec![];
+        };
+        let paths: Vec<_> = dirs.keys().cloned().collect();
+        for path in paths {
+            for ancestor in path.ancestors().skip(1).take_while(|dir| dir.starts_with(&top)) {
+                dirs.entry(ancestor.to_owned()).or_default();
+            }
+        }
+
+        // children before their parents
+        let mut order: Vec<_> = dirs.keys().cloned().collect();
+        order.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+        let mut hashes: HashMap<PathBuf, Hash> = HashMap::new();
+        for path in order {
+            let mut dir = dirs.remove(&path).expect("all directories are known");
+            let hash = (!dir.incomplete).then(|| {
+                dir.children.sort();
+                let mut hasher = blake3::Hasher::new();
+                for (kind, name, hash) in &dir.children {
+                    hasher.update(&[*kind]);
+                    hasher.update(&(name.len() as u64).to_le_bytes());
+                    hasher.update(name.as_encoded_bytes());
+                    hasher.update(hash);
+                }
+                hasher.finalize()
+            });
+
+            let parent = path.parent().filter(|_| path != top).map(Path::to_owned);
+            let name = path.file_name().map(|name| name.to_owned());
+            if let (Some(parent), Some(name)) = (parent, name) {
+                let (size, files) = (dir.size, dir.files);
+                let parent = dirs.entry(parent).or_default();
+                match hash {
+                    Some(hash) => parent.children.push((b'd', name, *hash.as_bytes())),
+                    None => parent.incomplete = true,
+                }
+                parent.size += size;
+                parent.files += files;
+                parent.root = parent.root.or(dir.root);
+            }
+            if let Some(hash) = hash.filter(|_| dir.files > 0) {
+                hashes.insert(path.clone(), hash);
+                let (size, files, root) = (dir.size, dir.files, dir.root);
+                dirs.insert(path, DirNode { size, files, root, ..Default::default() });
+            }
+        }
+
+        let mut trees: HashMap<Hash, DuplicateTree> = HashMap::new();
+        for (path, hash) in &hashes {
+            let dir = &dirs[path];
+            let tree = trees.entry(*hash).or_insert_with(|| DuplicateTree {
+                hash: *hash,
+                paths: vec![],
+                size: dir.size,
+                files: dir.files,
+            });
+            tree.paths.push(path.clone());
+        }
+        trees.retain(|_, tree| {
+            let spans_roots =
+                tree.paths.windows(2).any(|paths| dirs[&paths[0]].root != dirs[&paths[1]].root);
+            tree.paths.len() > 1 && self.root_filter().includes(spans_roots)
+        });
+        let duplicated: HashSet<&Path> =
+            trees.values().flat_map(|tree| tree.paths.iter().map(PathBuf::as_path)).collect();
+        let mut trees: Vec<_> = trees
+            .values()
+            .filter(|tree| {
+                !tree.paths.iter().all(|path| path.parent().is_some_and(|p| duplicated.contains(p)))
+            })
+            .cloned()
+            .collect();
+        for tree in &mut trees {
+            tree.paths.sort();
+        }
+        trees.sort_by(|a, b| {
+            b.wasted_bytes().cmp(&a.wasted_bytes()).then_with(|| a.paths.cmp(&b.paths))
+        });
+
+        trees
+    }
+
+    /// Attribute duplicated bytes to the directories that hold them.
+    ///
+    /// In each group of duplicates, the first file (by path) is considered the original, and the others redundant
+    /// copies. The size of each copy is attributed to its directory, and to all the directories above it, up to the
+    /// deepest directory that contains all the duplicates.
+    pub fn directory_report(&self) -> DirectoryReport {
+        let top = self
+            .duplicates()
+            .flat_map(|(_, entries)| entries.iter())
+            .filter_map(|path| path.parent().map(Path::to_owned))
+            .reduce(|common, dir| common_ancestor(&common, &dir))
+            .unwrap_or_default();
+        let mut directories: HashMap<PathBuf, (u64, usize)> = HashMap::new();
+        let mut pairs: HashMap<(PathBuf, PathBuf), (u64, usize)> = HashMap::new();
+        for (_, entries) in self.duplicates() {
+            let size = entries.file_size();
+            let paths: Vec<_> = entries.iter().collect();
+            for copy in &paths[1..] {
+                for dir in copy.ancestors().skip(1).take_while(|dir| dir.starts_with(&top)) {
+                    let waste = directories.entry(dir.to_owned()).or_default();
+                    waste.0 += size;
+                    waste.1 += 1;
+                }
+            }
+
+            let dirs: BTreeSet<_> = paths.iter().filter_map(|path| path.parent()).collect();
+            if dirs.len() > MAX_DIRS_PER_GROUP_FOR_PAIRS {
+                continue;
+            }
+            let dirs: Vec<_> = dirs.into_iter().collect();
+            for (i, first) in dirs.iter().enumerate() {
+                for second in &dirs[i + 1..] {
+                    let shared =
+                        pairs.entry((first.to_path_buf(), second.to_path_buf())).or_default();
+                    shared.0 += size;
+                    shared.1 += 1;
+                }
+            }
+        }
+
+        let mut directories: Vec<_> = directories
+            .into_iter()
+            .map(|(path, (wasted_bytes, redundant_files))| DirectoryWaste {
+                path,
+                wasted_bytes,
+                redundant_files,
+            })
+            .collect();
+        directories
+            .sort_by(|a, b| b.wasted_bytes.cmp(&a.wasted_bytes).then_with(|| a.path.cmp(&b.path)));
+        let mut pairs: Vec<_> = pairs
+            .into_iter()
+            .map(|((first, second), (shared_bytes, shared_files))| DirectoryPair {
+                first,
+                second,
+                shared_bytes,
+                shared_files,
+            })
+            .collect();
+        pairs.sort_by(|a, b| {
+            b.shared_bytes.cmp(&a.shared_bytes).then_with(|| a.paths().cmp(&b.paths()))
+        });
+
+        DirectoryReport { directories, pairs }
+    }
+}
+
+/// The deepest directory that contains both `a` and `b`.
+fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components().zip(b.components()).take_while(|(a, b)| a == b).map(|(a, _)| a).collect()
+}
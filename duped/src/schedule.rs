@@ -0,0 +1,140 @@
+//! Decides which hasher thread hashes which file, and in which order.
+
+use crate::{file::FilePath, hasher::ProgressiveHasher};
+
+use std::collections::BTreeMap;
+
+/// The order in which the files of a round are read.
+///
+/// On rotational disks, reading files in the order in which they were found makes the disk seek back and forth. The
+/// other orders sort the files of each round by their position on disk, and hand all the files of a device to the same
+/// hasher thread, so that each device is read roughly sequentially. This comes at a cost: hashing only starts once
+/// the walk is over, and a device is never read by more than one thread at a time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadOrder {
+    /// Files are hashed in the order in which they are found, by all hasher threads.
+    #[default]
+    Discovery,
+    /// Files are sorted by inode number, which most file systems allocate close to the data of the file.
+    Inode,
+    /// Files are sorted by the physical position of their first extent on disk. Falls back to the inode number of files
+    /// whose extents can't be queried (only Linux supports querying extents, with `FIEMAP`, and not on all file
+    /// systems).
+    Physical,
+}
+
+/// Split the hashers of a round between `threads` threads.
+pub(crate) fn distribute(
+    mut hashers: Vec<ProgressiveHasher>,
+    threads: usize,
+    order: ReadOrder,
+) -> Vec<Vec<ProgressiveHasher>> {
+    let mut batches: Vec<_> = (0..threads).map(|_| vec![]).collect();
+    if order == ReadOrder::Discovery {
+        let chunk_size = (hashers.len() / threads).max(1);
+        let mut i = 0;
+        while !hashers.is_empty() {
+            let size = chunk_size.min(hashers.len());
+            batches[i % threads].extend(hashers.drain(..size));
+
+            i += 1;
+        }
+        return batches;
+    }
+
+    let mut devices: BTreeMap<u64, Vec<(u64, ProgressiveHasher)>> = BTreeMap::new();
+    for hasher in hashers {
+        let (device, position) = position(hasher.file_path(), order);
+        devices.entry(device).or_default().push((position, hasher));
+    }
+    for (i, mut files) in devices.into_values().enumerate() {
+        files.sort_by_key(|(position, _)| *position);
+        batches[i % threads].extend(files.into_iter().map(|(_, hasher)| hasher));
+    }
+
+    batches
+}
+
+/// Returns the device of a file, and its position on that device.
+fn position(file_path: &FilePath, order: ReadOrder) -> (u64, u64) {
+    let position = match order {
+        #[cfg(target_os = "linux")]
+        ReadOrder::Physical => first_extent(&file_path.path()).unwrap_or(file_path.inode()),
+        _ => file_path.inode(),
+    };
+
+    (file_path.device(), position)
+}
+
+/// Returns the physical offset of the first extent of a file, using the `FS_IOC_FIEMAP` ioctl.
+#[cfg(target_os = "linux")]
+fn first_extent(path: &std::path::Path) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+
+    /// `struct fiemap_extent` from `linux/fiemap.h`.
+    #[repr(C)]
+    #[derive(Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    /// `struct fiemap` from `linux/fiemap.h`, with room for a single extent.
+    #[repr(C)]
+    #[derive(Default)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; 1],
+    }
+
+    /// `_IOWR('f', 11, struct fiemap)`.
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xC020_660B;
+    /// The location of the extent is not known yet, e.g. because it wasn't written to disk yet.
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+
+    let file = crate::hasher::open_regular_file(path).ok()?;
+    let mut fiemap = Fiemap { length: u64::MAX, extent_count: 1, ..Default::default() };
+    // SAFETY: `fiemap` has room for `extent_count` extents, and outlives the call.
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+
+    let extent = &fiemap.extents[0];
+    let known = res == 0 && fiemap.mapped_extents > 0 && extent.flags & FIEMAP_EXTENT_UNKNOWN == 0;
+
+    known.then_some(extent.physical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::testing::hasher;
+
+    fn hashers(dir: &std::path::Path, count: usize) -> Vec<ProgressiveHasher> {
+        (0..count).map(|i| hasher(dir, &i.to_string(), &[i as u8; 8192])).collect()
+    }
+
+    #[test]
+    fn files_of_a_device_go_to_one_thread_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        for order in [ReadOrder::Inode, ReadOrder::Physical] {
+            let batches = distribute(hashers(dir.path(), 10), 3, order);
+            let sizes: Vec<_> = batches.iter().map(Vec::len).collect();
+            assert_eq!(sizes, [10, 0, 0]);
+            let positions: Vec<_> =
+                batches[0].iter().map(|h| position(h.file_path(), order)).collect();
+            assert!(positions.windows(2).all(|w| w[0] <= w[1]));
+        }
+
+        let batches = distribute(hashers(dir.path(), 10), 3, ReadOrder::Discovery);
+        let sizes: Vec<_> = batches.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [4, 3, 3]);
+    }
+}
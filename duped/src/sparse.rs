@@ -0,0 +1,161 @@
+//! Hashes sparse files without reading their holes.
+//!
+//! Disk images and database files are often mostly holes, i.e. regions that were never written to, which read as zeros
+//! without being stored on disk. Holes are found with `SEEK_DATA` and `SEEK_HOLE`, and hashed as zeros, so the hash of
+//! a sparse file is the same as the hash of a regular file with the same content.
+
+use std::fs::{File, Metadata};
+
+/// The zeros fed to a hasher in place of a hole.
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+/// A region of a file, which is either data that has to be read, or a hole.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) hole: bool,
+}
+
+/// Returns whether a file is sparse, i.e. whether it takes less space on disk than its size.
+pub(crate) fn is_sparse(metadata: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        metadata.blocks().saturating_mul(512) < metadata.len()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// Split `len` bytes of `file` at `offset` into data and holes.
+///
+/// The whole region is data if `sparse` is `false`, or if holes can't be found (only Linux supports finding holes,
+/// and not on all file systems).
+pub(crate) fn segments(file: &File, offset: u64, len: u64, sparse: bool) -> Vec<Segment> {
+    let data = vec![Segment { offset, len, hole: false }];
+    if !sparse || len == 0 {
+        return data;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        find_holes(file, offset, len).unwrap_or(data)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = file;
+        data
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn find_holes(file: &File, offset: u64, len: u64) -> std::io::Result<Vec<Segment>> {
+    use std::{io, os::fd::AsRawFd};
+
+    let seek = |position: u64, whence| {
+        // SAFETY: the file descriptor is valid for the lifetime of `file`.
+        let res = unsafe { libc::lseek(file.as_raw_fd(), position as libc::off_t, whence) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(res as u64)
+        }
+    };
+
+    let end = offset + len;
+    let mut segments = vec![];
+    let mut position = offset;
+    while position < end {
+        let data = match seek(position, libc::SEEK_DATA) {
+            Ok(data) => data.min(end),
+            // there is no data after `position`
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => end,
+            Err(e) => return Err(e),
+        };
+        if data > position {
+            segments.push(Segment { offset: position, len: data - position, hole: true });
+        }
+        if data == end {
+            break;
+        }
+
+        let hole = seek(data, libc::SEEK_HOLE)?.min(end);
+        segments.push(Segment { offset: data, len: hole - data, hole: false });
+        position = hole;
+    }
+
+    Ok(segments)
+}
+
+/// Pass `len` zeros to `feed`, in slices.
+pub(crate) fn feed_zeros(mut len: u64, mut feed: impl FnMut(&[u8])) {
+    while len > 0 {
+        let size = len.min(ZEROS.len() as u64) as usize;
+        feed(&ZEROS[..size]);
+        len -= size as u64;
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holes_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let file = File::create(&path).unwrap();
+        let mib = 1024 * 1024;
+        file.set_len(8 * mib).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&file, &[1; 4096], 4 * mib).unwrap();
+        file.sync_all().unwrap();
+        let file = File::open(&path).unwrap();
+        if !is_sparse(&file.metadata().unwrap()) {
+            // the file system doesn't support sparse files
+            return;
+        }
+
+        let segments = segments(&file, 1024, 8 * mib - 2048, true);
+        assert_eq!(
+            segments,
+            [
+                Segment { offset: 1024, len: 4 * mib - 1024, hole: true },
+                Segment { offset: 4 * mib, len: 4096, hole: false },
+                Segment { offset: 4 * mib + 4096, len: 4 * mib - 4096 - 1024, hole: true },
+            ]
+        );
+        assert_eq!(super::segments(&file, 0, 10, false).len(), 1);
+    }
+
+    #[test]
+    fn sparse_files_hash_like_their_content() {
+        use crate::{
+            hasher::testing::{file_hasher, finish},
+            hasher::HasherConfig,
+            ChunkSchedule,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let mut data = vec![0; 3 * 1024 * 1024 + 5];
+        data[1024 * 1024..1024 * 1024 + 100].fill(7);
+        let file = File::create(&path).unwrap();
+        file.set_len(data.len() as u64).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(
+            &file,
+            &data[1024 * 1024..1024 * 1024 + 100],
+            1024 * 1024,
+        )
+        .unwrap();
+        drop(file);
+
+        let config =
+            HasherConfig { chunks: ChunkSchedule::fixed(1024 * 1024 + 3), ..Default::default() };
+        assert_eq!(finish(&mut file_hasher(&path), &config), blake3::hash(&data));
+    }
+}
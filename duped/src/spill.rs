@@ -0,0 +1,252 @@
+//! Keeps the candidates of a round on disk, rather than in memory.
+//!
+//! On volumes with tens of millions of files, the files that are still candidates for duplication after a round can
+//! take more memory than is available. A [`CandidateList`] keeps a limited number of hashers in memory, and appends the
+//! others to an unnamed temporary file, from which they are read back when the next round is dispatched.
+//!
+//! Hashers that already started hashing their file sequentially hold a blake3 hasher, whose state can't be serialized,
+//! so they always stay in memory. Since files are told apart by their probes first, few files get that far.
+
+use crate::{file::DirInterner, hasher::ProgressiveHasher};
+
+use tracing::error;
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Where candidates are spilled, and when.
+#[derive(Clone, Debug)]
+pub(crate) struct SpillConfig {
+    /// The directory in which spill files are created.
+    pub(crate) dir: PathBuf,
+    /// How many candidates are kept in memory before spilling the others.
+    pub(crate) max_in_memory: usize,
+}
+
+/// A list of hashers, which spills hashers to disk once it holds too many of them.
+pub(crate) struct CandidateList {
+    config: Option<SpillConfig>,
+    memory: Vec<ProgressiveHasher>,
+    spilled: Option<SpillFile>,
+    len: usize,
+    /// The sum of [`ProgressiveHasher::bytes_left`] over all hashers.
+    bytes_left: u64,
+}
+
+impl CandidateList {
+    /// Create an empty list, which spills to disk according to `config`, or never if it is `None`.
+    pub(crate) fn new(config: Option<SpillConfig>) -> Self {
+        Self { config, memory: vec![], spilled: None, len: 0, bytes_left: 0 }
+    }
+
+    /// Create an empty list, with the same configuration as `self`.
+    pub(crate) fn empty_like(&self) -> Self {
+        Self::new(self.config.clone())
+    }
+
+    /// The number of hashers in the list.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// How many bytes the hashers of the list have left to hash sequentially, at most.
+    pub(crate) fn bytes_left(&self) -> u64 {
+        self.bytes_left
+    }
+
+    /// Returns `true` if the list is empty.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn push(&mut self, hasher: ProgressiveHasher) {
+        self.len += 1;
+        self.bytes_left += hasher.bytes_left();
+        let Some(config) = self.config.as_ref().filter(|c| self.memory.len() >= c.max_in_memory)
+        else {
+            self.memory.push(hasher);
+            return;
+        };
+
+        let mut record = vec![];
+        if !hasher.encode(&mut record) {
+            self.memory.push(hasher);
+            return;
+        }
+        let spilled = match self.spilled.take() {
+            Some(spilled) => Ok(spilled),
+            None => SpillFile::create(&config.dir),
+        };
+        match spilled.and_then(|mut spilled| spilled.write(&record).map(|()| spilled)) {
+            Ok(spilled) => self.spilled = Some(spilled),
+            Err(e) => {
+                error!(error = %e, dir = %config.dir.display(), "failed to spill candidates, keeping them in memory");
+                // don't try again
+                self.config = None;
+                self.memory.push(hasher);
+            }
+        }
+    }
+
+    /// Return all the hashers, reading back the spilled ones.
+    ///
+    /// Hashers that can't be read back are logged and dropped, so the iterator might yield fewer hashers than were
+    /// pushed.
+    pub(crate) fn into_hashers(self) -> impl Iterator<Item = ProgressiveHasher> {
+        let spilled = self.spilled.map(|spilled| match spilled.into_reader() {
+            Ok(reader) => Some((reader, DirInterner::default())),
+            Err(e) => {
+                error!(error = %e, "failed to read spilled candidates back");
+                None
+            }
+        });
+        let mut spilled = spilled.flatten();
+        let read_back = std::iter::from_fn(move || {
+            let (reader, dirs) = spilled.as_mut()?;
+            match read_record(reader) {
+                Ok(Some(record)) => {
+                    let hasher = ProgressiveHasher::decode(&mut &record[..], dirs);
+                    if hasher.is_none() {
+                        error!("failed to decode a spilled candidate");
+                    }
+                    hasher
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    error!(error = %e, "failed to read spilled candidates back");
+                    None
+                }
+            }
+        });
+
+        self.memory.into_iter().chain(read_back)
+    }
+}
+
+/// An unnamed temporary file that holds length-prefixed records.
+struct SpillFile {
+    writer: BufWriter<File>,
+    /// The path of the file, if it couldn't be removed when it was created.
+    path: Option<PathBuf>,
+}
+
+impl SpillFile {
+    fn create(dir: &Path) -> io::Result<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!(".duped-spill-{}-{id}", std::process::id()));
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        // the file is only accessed through its handle, so it can be removed straight away where that is allowed (the
+        // file is removed once it is dropped otherwise)
+        let path = fs::remove_file(&path).is_err().then_some(path);
+
+        Ok(Self { writer: BufWriter::new(file), path })
+    }
+
+    fn write(&mut self, record: &[u8]) -> io::Result<()> {
+        self.writer.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.writer.write_all(record)
+    }
+
+    fn into_reader(mut self) -> io::Result<BufReader<SpillFile>> {
+        self.writer.flush()?;
+        self.writer.get_mut().rewind()?;
+
+        Ok(BufReader::new(self))
+    }
+}
+
+impl Read for SpillFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.writer.get_mut().read(buf)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        // a file that was already removed is not removed again, since another file might have its name by now
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Read the next record, or return `None` at the end of the file.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut record = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut record)?;
+
+    Ok(Some(record))
+}
+
+/// Append `value` to an encoded record.
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Append length-prefixed `bytes` to an encoded record.
+pub(crate) fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Take a value appended with [`put_u64`] from the start of `input`.
+pub(crate) fn take_u64(input: &mut &[u8]) -> Option<u64> {
+    let (value, rest) = input.split_first_chunk()?;
+    *input = rest;
+
+    Some(u64::from_le_bytes(*value))
+}
+
+/// Take bytes appended with [`put_bytes`] from the start of `input`.
+pub(crate) fn take_bytes<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = usize::try_from(take_u64(input)?).ok()?;
+    if input.len() < len {
+        return None;
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::{testing::hasher, HasherConfig};
+
+    #[test]
+    fn spilled_candidates_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HasherConfig::default();
+        let mut list =
+            CandidateList::new(Some(SpillConfig { dir: dir.path().to_owned(), max_in_memory: 2 }));
+        let mut expected = vec![];
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        for i in 0..10 {
+            let mut hasher = hasher(&sub, &i.to_string(), &vec![i as u8; 64 * 1024]);
+            hasher.update(&config).unwrap();
+            expected.push((sub.join(i.to_string()), hasher.current_hash()));
+            list.push(hasher);
+        }
+        assert!(!list.is_empty());
+        // the spill file is unnamed, so only `sub` is left in the directory
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let read_back: Vec<_> =
+            list.into_hashers().map(|h| (h.file_path().path(), h.current_hash())).collect();
+        assert_eq!(read_back, expected);
+    }
+}
@@ -0,0 +1,84 @@
+//! Statistics about where a search spent its time, and how much work it did.
+
+use std::time::Duration;
+
+/// Statistics gathered during [`crate::Deduper::find`], see [`crate::DeduperResult::stats`].
+#[derive(Clone, Debug, Default)]
+pub struct ScanStats {
+    pub(crate) walk_duration: Duration,
+    pub(crate) rounds: Vec<RoundStats>,
+    pub(crate) files_opened: u64,
+    pub(crate) eliminated_by_size: usize,
+    pub(crate) eliminated_by_partial_hash: usize,
+    pub(crate) eliminated_by_full_hash: usize,
+}
+
+impl ScanStats {
+    /// How long it took to walk the roots.
+    ///
+    /// The first round of hashing starts while the roots are being walked, so this overlaps with the first round.
+    pub fn walk_duration(&self) -> Duration {
+        self.walk_duration
+    }
+
+    /// The rounds of hashing, in order.
+    pub fn rounds(&self) -> &[RoundStats] {
+        &self.rounds
+    }
+
+    /// The total time spent hashing, over all rounds.
+    pub fn hash_duration(&self) -> Duration {
+        self.rounds.iter().map(|round| round.duration).sum()
+    }
+
+    /// How many bytes were read from disk, over all rounds. The holes of sparse files are not read.
+    pub fn bytes_read(&self) -> u64 {
+        self.rounds.iter().map(|round| round.bytes_read).sum()
+    }
+
+    /// How many times a file was opened to be read, including the attempts that failed (see
+    /// [`crate::DeduperResult::skipped`]). Files are opened once per round they take part in.
+    pub fn files_opened(&self) -> u64 {
+        self.files_opened
+    }
+
+    /// How many files were eliminated because no other file has the same size. These files are never read.
+    pub fn eliminated_by_size(&self) -> usize {
+        self.eliminated_by_size
+    }
+
+    /// How many files were eliminated because their partial hash is unique, i.e. before being read entirely.
+    pub fn eliminated_by_partial_hash(&self) -> usize {
+        self.eliminated_by_partial_hash
+    }
+
+    /// How many files were read entirely, but turned out to have no duplicates.
+    pub fn eliminated_by_full_hash(&self) -> usize {
+        self.eliminated_by_full_hash
+    }
+}
+
+/// Statistics about a round of hashing.
+#[derive(Clone, Debug)]
+pub struct RoundStats {
+    pub(crate) duration: Duration,
+    pub(crate) candidates: usize,
+    pub(crate) bytes_read: u64,
+}
+
+impl RoundStats {
+    /// How long the round took, from the moment its first candidate was known to the moment all of them were hashed.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// How many files were hashed during the round.
+    pub fn candidates(&self) -> usize {
+        self.candidates
+    }
+
+    /// How many bytes were read from disk during the round.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
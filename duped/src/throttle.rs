@@ -0,0 +1,207 @@
+//! Limits how fast files are read, so that a search can run next to other workloads.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The longest a thread sleeps before checking whether the limits changed.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// A token bucket, which holds up to a second worth of tokens.
+///
+/// Taking more tokens than the bucket holds puts it in debt, which has to be paid off before tokens can be taken again.
+/// This way, a large read doesn't have to be split to fit in the bucket.
+#[derive(Debug)]
+struct Bucket {
+    /// How many tokens are added per second, or `None` if the bucket is unlimited.
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    /// Create a full bucket. A rate of 0 means the bucket is unlimited.
+    fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|&rate| rate > 0);
+        Self { rate, tokens: rate.unwrap_or(0) as f64, refilled: Instant::now() }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        let rate = rate.filter(|&rate| rate > 0);
+        self.refill();
+        self.rate = rate;
+        if rate.is_none() {
+            self.tokens = 0.0;
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let refilled =
+                self.tokens + now.duration_since(self.refilled).as_secs_f64() * rate as f64;
+            self.tokens = refilled.min(rate as f64);
+        }
+        self.refilled = now;
+    }
+
+    /// Returns how long to wait until the bucket is out of debt.
+    fn wait_time(&mut self) -> Option<Duration> {
+        self.refill();
+        match self.rate {
+            Some(rate) if self.tokens < 0.0 => {
+                Some(Duration::from_secs_f64(-self.tokens / rate.max(1) as f64))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Limits the number of bytes read, and the number of files opened, per second by the hasher threads.
+///
+/// A throttle is a handle: clones share the same limits, so the limits of a running search can be changed by keeping a
+/// clone of the throttle passed to [`crate::DeduperBuilder::throttle`]. Files are opened once per round, so a file that
+/// is hashed in multiple rounds counts as multiple files.
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    inner: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    bytes: Bucket,
+    files: Bucket,
+}
+
+impl Throttle {
+    /// Create a new throttle, with a maximum number of bytes read and files opened per second (`None` means no limit).
+    ///
+    /// A limit of 0 would never let anything through, so it means no limit as well.
+    pub fn new(bytes_per_sec: Option<u64>, files_per_sec: Option<u64>) -> Self {
+        let buckets =
+            Buckets { bytes: Bucket::new(bytes_per_sec), files: Bucket::new(files_per_sec) };
+        Self { inner: Arc::new(Mutex::new(buckets)) }
+    }
+
+    /// Create a throttle without limits.
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    /// Returns the maximum number of bytes read per second.
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.inner.lock().expect("poisoned lock").bytes.rate
+    }
+
+    /// Returns the maximum number of files opened per second.
+    pub fn files_per_sec(&self) -> Option<u64> {
+        self.inner.lock().expect("poisoned lock").files.rate
+    }
+
+    /// Change the maximum number of bytes read per second (`None` or 0 mean no limit). Threads that are waiting pick up
+    /// the change shortly.
+    pub fn set_bytes_per_sec(&self, rate: Option<u64>) {
+        self.inner.lock().expect("poisoned lock").bytes.set_rate(rate);
+    }
+
+    /// Change the maximum number of files opened per second (`None` or 0 mean no limit). Threads that are waiting pick
+    /// up the change shortly.
+    pub fn set_files_per_sec(&self, rate: Option<u64>) {
+        self.inner.lock().expect("poisoned lock").files.set_rate(rate);
+    }
+
+    /// Take `bytes` and `files` out of the buckets, and wait until the buckets are out of debt.
+    pub(crate) fn acquire(&self, bytes: u64, files: u64) {
+        {
+            let mut buckets = self.inner.lock().expect("poisoned lock");
+            let Buckets { bytes: bytes_bucket, files: files_bucket } = &mut *buckets;
+            for (bucket, taken) in [(bytes_bucket, bytes), (files_bucket, files)] {
+                bucket.refill();
+                if bucket.rate.is_some() {
+                    bucket.tokens -= taken as f64;
+                }
+            }
+        }
+
+        loop {
+            let wait = {
+                let mut buckets = self.inner.lock().expect("poisoned lock");
+                buckets.bytes.wait_time().max(buckets.files.wait_time())
+            };
+            match wait {
+                Some(wait) => std::thread::sleep(wait.min(MAX_SLEEP)),
+                None => return,
+            }
+        }
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+/// Set the I/O scheduling class of the calling thread to idle, so that it only gets disk time when no other process
+/// needs it.
+///
+/// Only Linux supports I/O priorities, and only some I/O schedulers (e.g. BFQ) honour them.
+pub(crate) fn set_idle_io_priority() -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+        // a pid of 0 means the calling thread
+        // SAFETY: `ioprio_set` doesn't access memory.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_limits_and_adjusts_rate() {
+        let throttle = Throttle::new(Some(1000), None);
+        let start = Instant::now();
+        // the bucket starts with 1000 tokens, so this takes at least 200ms
+        throttle.acquire(1200, 1);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let clone = throttle.clone();
+        clone.set_bytes_per_sec(None);
+        assert_eq!(throttle.bytes_per_sec(), None);
+        let start = Instant::now();
+        throttle.acquire(1 << 40, 1 << 20);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn zero_rates_are_unlimited() {
+        let throttle = Throttle::new(Some(0), Some(0));
+        assert_eq!((throttle.bytes_per_sec(), throttle.files_per_sec()), (None, None));
+        throttle.set_bytes_per_sec(Some(1000));
+        throttle.set_bytes_per_sec(Some(0));
+        assert_eq!(throttle.bytes_per_sec(), None);
+        let start = Instant::now();
+        throttle.acquire(1 << 40, 1 << 20);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -3,7 +3,10 @@
 //!
 //! See also: [`NoopStopper`], [`CotentLimit`], and [`NoopFindHook`].
 
-use crate::duplicates::FileEntry;
+use crate::{
+    combinator::{And, Not, Or},
+    duplicates::FileEntry,
+};
 
 use blake3::Hash;
 
@@ -25,6 +28,15 @@ pub type FilterAction = ControlFlow<(), FileAction>;
 /// [`crate::Deduper`] calls [`Self::include_file`] for every file it encounters while recursing into the
 /// configured roots.
 pub trait DeduperFileFilter {
+    /// Called whenever the entries passed to the filter switch to another root, `root`.
+    ///
+    /// Roots are walked in parallel, so their entries are interleaved, and this can be called several times for the
+    /// same root. All paths passed to [`Self::handle_file`] and [`Self::handle_dir`] until the next call are
+    /// descendants of `root`. This is useful for filters that match paths relative to the root they belong to.
+    ///
+    /// The default implementation does nothing.
+    fn root_entered(&mut self, _root: &Path) {}
+
     /// Return whether the given file should be processed by [`crate::Deduper`].
     ///
     /// # Arguments
@@ -40,13 +52,83 @@ pub trait DeduperFileFilter {
     fn handle_file(&mut self, _path: &Path, _metadata: &fs::Metadata) -> FilterAction {
         FilterAction::Continue(FileAction::Include)
     }
+
+    /// Return whether [`crate::Deduper`] should descend into the given directory.
+    ///
+    /// Excluding a directory skips its entire subtree, so none of the files underneath it are passed to
+    /// [`Self::handle_file`]. The roots themselves are always walked.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the directory.
+    /// * `metadata` - The filesystem metadata of the directory.
+    ///
+    /// # Notes
+    ///
+    /// By default, all directories are walked.
+    fn handle_dir(&mut self, _path: &Path, _metadata: &fs::Metadata) -> FilterAction {
+        FilterAction::Continue(FileAction::Include)
+    }
+
+    /// Combine this filter with `other`, such that a file is included only if both filters include it.
+    fn and<F: DeduperFileFilter>(self, other: F) -> And<Self, F>
+    where
+        Self: Sized,
+    {
+        And::new(self, other)
+    }
+
+    /// Combine this filter with `other`, such that a file is included if any of the two filters include it.
+    fn or<F: DeduperFileFilter>(self, other: F) -> Or<Self, F>
+    where
+        Self: Sized,
+    {
+        Or::new(self, other)
+    }
+
+    /// Invert this filter, such that excluded files are included, and vice versa.
+    fn not(self) -> Not<Self>
+    where
+        Self: Sized,
+    {
+        Not::new(self)
+    }
 }
 
-/// [`crate::Deduper`] calls [`Self::entry_processed`] for every file it hashed successfully.
+/// [`crate::Deduper`] calls [`Self::entry_processed`] for every file it hashed successfully, and the other methods as
+/// the search progresses.
+///
+/// A search first walks the roots, then hashes the files that were selected in rounds (the first round starts while
+/// the roots are still being walked). After each round, the files whose (partial) hash is unique are eliminated, and
+/// the others are candidates for the next round. All methods do nothing by default.
 pub trait DeduperFindHook: Send + Sync + 'static {
+    /// Called regularly while the roots are walked, with the number of directories and files found so far (whether the
+    /// filter selects them or not), and once more with `done` set when the walk is over.
+    fn walk_progress(&self, _dirs: u64, _files: u64, _done: bool) {}
+
     /// Called on start to send the implementor the number of files that are going to be processed.
     fn files_selected(&self, _size: usize) {}
 
+    /// Called once all the candidates of a round are known, i.e. at the end of the walk for the first round.
+    ///
+    /// `bytes_left` is the number of bytes of the candidates that weren't hashed yet. At most that many bytes are going
+    /// to be hashed until the end of the search, on top of the probes (see [`crate::ProbeSchedule`]). The candidates of
+    /// the first round are all the selected files.
+    fn round_started(&self, _round: u32, _candidates: usize, _bytes_left: u64) {}
+
+    /// Called when a round is over, with the number of candidates it eliminated, and the number of candidates left
+    /// for the next round. Candidates that were hashed entirely, or that couldn't be read, are neither.
+    fn round_finished(&self, _round: u32, _eliminated: usize, _remaining: usize) {}
+
+    /// Called every time a file was read for a round, with the number of bytes that were hashed.
+    fn bytes_hashed(&self, _bytes: u64) {}
+
+    /// Called when candidates are eliminated before being hashed entirely, with the number of bytes that won't be read
+    /// because of that.
+    ///
+    /// Files whose size is unique are eliminated without being read at all.
+    fn candidates_eliminated(&self, _count: usize, _bytes_saved: u64) {}
+
     /// Hook that is called when the [`crate::Deduper`] finished hashing a file.
     ///
     /// Users are encouraged to use this method to get updates on the progress of [`crate::Deduper::find`].
@@ -0,0 +1,479 @@
+//! Reads files with [io_uring](https://man7.org/linux/man-pages/man7/io_uring.7.html), which keeps many reads in
+//! flight at the same time, instead of blocking on one read at a time.
+//!
+//! Each hasher thread owns a ring, and a fixed set of buffers, one per read that can be in flight. The regions of a
+//! [`Round`] are split into blocks that are read concurrently, possibly out of order, and fed to the hasher in order as
+//! soon as all the blocks before them were fed. The holes of sparse files are never read: they are fed as zeros once
+//! their turn comes.
+
+use crate::{
+    cache::{self, CacheMode},
+    hasher::{open_regular_file, HasherConfig, ProgressiveHasher, Round},
+    sparse,
+};
+
+use io_uring::{opcode, types, IoUring};
+
+use std::{collections::BTreeMap, fs::File, io, os::fd::AsRawFd};
+
+/// The size of each read.
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// A file whose round is in progress.
+struct Task {
+    hasher: ProgressiveHasher,
+    round: Round,
+    /// The file, or `None` if it couldn't be opened.
+    file: Option<File>,
+    /// Whether blocks are dropped from the page cache once they are hashed.
+    drop_behind: bool,
+    /// The first error that occurred while reading the file.
+    result: io::Result<()>,
+    /// The offset and length of each block of the round, in order, and whether the block is a hole.
+    blocks: Vec<(u64, usize, bool)>,
+    /// The next block to submit.
+    next_submit: usize,
+    /// The next block to feed to the hasher.
+    next_feed: usize,
+    /// Blocks that were read, but can't be fed yet, because a block before them is still being read. Maps the index of
+    /// the block to the buffer it was read into, or to `None` if the block is a hole.
+    ready: BTreeMap<usize, Option<usize>>,
+    /// How many reads of this file are in flight.
+    in_flight: usize,
+}
+
+impl Task {
+    fn new(mut hasher: ProgressiveHasher, config: &HasherConfig) -> Self {
+        let round = hasher.next_round(config);
+        config.throttle.acquire(round.len(), 1);
+        let (file, result) = match open_regular_file(&hasher.file_path().path()) {
+            Ok(file) => (Some(file), Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let drop_behind = config.cache != CacheMode::Cached;
+        if let Some(file) = file.as_ref().filter(|_| drop_behind && round.is_sequential()) {
+            cache::advise_sequential(file);
+        }
+        let sparse = hasher.file_path().is_sparse();
+        let mut blocks = vec![];
+        for &(offset, len) in round.regions() {
+            let segments = match &file {
+                Some(file) => sparse::segments(file, offset, len, sparse),
+                None => vec![sparse::Segment { offset, len, hole: false }],
+            };
+            for segment in segments {
+                blocks.extend((0..segment.len).step_by(BLOCK_SIZE).map(|start| {
+                    let len = (segment.len - start).min(BLOCK_SIZE as u64) as usize;
+                    (segment.offset + start, len, segment.hole)
+                }));
+            }
+        }
+
+        Self {
+            hasher,
+            round,
+            file,
+            drop_behind,
+            result,
+            blocks,
+            next_submit: 0,
+            next_feed: 0,
+            ready: BTreeMap::new(),
+            in_flight: 0,
+        }
+    }
+
+    /// Returns whether there are blocks left to submit.
+    fn wants_submit(&self) -> bool {
+        self.result.is_ok() && self.next_submit < self.blocks.len()
+    }
+
+    /// Returns whether the round is over, either because all blocks were fed, or because of an error.
+    fn is_done(&self) -> bool {
+        match self.result {
+            Ok(()) => self.next_feed == self.blocks.len(),
+            Err(_) => self.in_flight == 0,
+        }
+    }
+
+    fn fail(&mut self, error: io::Error) {
+        if self.result.is_ok() {
+            self.result = Err(error);
+        }
+    }
+}
+
+/// A read that was submitted to the ring.
+///
+/// Each read uses its own buffer, so reads are identified by the index of their buffer.
+#[derive(Clone, Copy)]
+struct Read {
+    /// The index of the task the read belongs to.
+    task: usize,
+    /// The index of the block that is read.
+    block: usize,
+    /// How many bytes of the block were read so far.
+    filled: usize,
+}
+
+/// Hashes batches of files using io_uring.
+pub(crate) struct UringReader {
+    ring: IoUring,
+    buffers: Vec<Box<[u8]>>,
+    /// The read that uses each buffer, if any.
+    reads: Vec<Option<Read>>,
+    /// Buffers that are not used by any read.
+    free: Vec<usize>,
+}
+
+impl UringReader {
+    /// Create a new reader that keeps up to `queue_depth` reads in flight.
+    pub(crate) fn new(queue_depth: u32) -> io::Result<Self> {
+        let queue_depth = queue_depth.max(1);
+        let ring = IoUring::new(queue_depth)?;
+        let buffers = (0..queue_depth).map(|_| vec![0; BLOCK_SIZE].into_boxed_slice()).collect();
+
+        Ok(Self {
+            ring,
+            buffers,
+            reads: vec![None; queue_depth as usize],
+            free: (0..queue_depth as usize).rev().collect(),
+        })
+    }
+
+    /// Run a round of hashing on each of the `hashers`, and pass them to `done` as soon as their round is over.
+    ///
+    /// Stops early if `done` returns `false`. If the ring fails, the hashers that weren't passed to `done` yet are
+    /// passed along with the error, and the error is returned: the reader must not be used after that.
+    pub(crate) fn hash(
+        &mut self,
+        hashers: Vec<ProgressiveHasher>,
+        config: &HasherConfig,
+        mut done: impl FnMut(ProgressiveHasher, io::Result<()>) -> bool,
+    ) -> io::Result<()> {
+        let mut tasks: Vec<_> =
+            hashers.into_iter().map(|hasher| Some(Task::new(hasher, config))).collect();
+        let res = self.run(&mut tasks, &mut done);
+        if let Err(e) = &res {
+            for task in tasks.into_iter().flatten() {
+                if !done(task.hasher, Err(io::Error::new(e.kind(), e.to_string()))) {
+                    break;
+                }
+            }
+        }
+
+        res
+    }
+
+    fn run(
+        &mut self,
+        tasks: &mut [Option<Task>],
+        done: &mut impl FnMut(ProgressiveHasher, io::Result<()>) -> bool,
+    ) -> io::Result<()> {
+        let mut left = tasks.len();
+        // tasks before this one have no blocks left to submit
+        let mut next_task = 0;
+
+        loop {
+            for slot in tasks.iter_mut() {
+                if slot.as_ref().is_some_and(Task::is_done) {
+                    let task = slot.take().expect("task is there");
+                    left -= 1;
+                    if !done(task.hasher, task.result) {
+                        return Ok(());
+                    }
+                }
+            }
+            if left == 0 {
+                return Ok(());
+            }
+
+            while !self.free.is_empty() {
+                while next_task < tasks.len()
+                    && !tasks[next_task].as_ref().is_some_and(Task::wants_submit)
+                {
+                    next_task += 1;
+                }
+                let Some(task) = tasks.get_mut(next_task).and_then(Option::as_mut) else {
+                    break;
+                };
+
+                let block = task.next_submit;
+                task.next_submit += 1;
+                if task.blocks[block].2 {
+                    // holes are not read, but still have to be fed in order
+                    task.ready.insert(block, None);
+                    self.feed_ready(task);
+                    continue;
+                }
+                let buffer = self.free.pop().expect("a buffer is free");
+                let read = Read { task: next_task, block, filled: 0 };
+                task.in_flight += 1;
+                self.submit(buffer, read, task)?;
+            }
+            if tasks.iter().flatten().any(Task::is_done) {
+                // rounds made only of holes are over without any read
+                continue;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+
+            let completed: Vec<_> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                .collect();
+            for (buffer, result) in completed {
+                let read = self.reads[buffer].as_ref().expect("completed read exists");
+                let task =
+                    tasks[read.task].as_mut().expect("tasks with reads in flight are not done");
+                self.complete(buffer, result, task)?;
+            }
+        }
+    }
+
+    /// Handle the completion of the read that uses `buffer`, which belongs to `task`, given the `result` of the read.
+    fn complete(&mut self, buffer: usize, result: i32, task: &mut Task) -> io::Result<()> {
+        let mut read = self.reads[buffer].take().expect("completed read exists");
+        let (_, len, _) = task.blocks[read.block];
+
+        match result {
+            result if result == -libc::EAGAIN || result == -libc::EINTR => {
+                return self.submit(buffer, read, task);
+            }
+            result if result < 0 => task.fail(io::Error::from_raw_os_error(-result)),
+            0 => task.fail(io::ErrorKind::UnexpectedEof.into()),
+            result => {
+                read.filled += result as usize;
+                if read.filled < len && task.result.is_ok() {
+                    // a short read, so read the rest of the block
+                    return self.submit(buffer, read, task);
+                }
+            }
+        }
+
+        task.in_flight -= 1;
+        if task.result.is_err() {
+            // the blocks of a failed task are never fed, so the buffers of the blocks that were read are given back
+            // right away, along with the buffers of the blocks waiting for an earlier block
+            self.free.push(buffer);
+            self.free.extend(std::mem::take(&mut task.ready).into_values().flatten());
+            return Ok(());
+        }
+        task.ready.insert(read.block, Some(buffer));
+        self.feed_ready(task);
+
+        Ok(())
+    }
+
+    /// Feed the blocks of `task` that were read, as long as all the blocks before them were fed.
+    fn feed_ready(&mut self, task: &mut Task) {
+        while let Some(buffer) = task.ready.remove(&task.next_feed) {
+            let (offset, len, _) = task.blocks[task.next_feed];
+            task.next_feed += 1;
+            let Some(buffer) = buffer else {
+                task.hasher.feed_hole(&task.round, len as u64);
+                continue;
+            };
+            task.hasher.feed(&task.round, &self.buffers[buffer][..len]);
+            if let Some(file) = task.file.as_ref().filter(|_| task.drop_behind) {
+                cache::drop_from_cache(file, offset, len as u64);
+            }
+            self.free.push(buffer);
+        }
+    }
+
+    /// Submit the unread part of a block, to be read into `buffer`.
+    fn submit(&mut self, buffer: usize, read: Read, task: &Task) -> io::Result<()> {
+        let (offset, len, _) = task.blocks[read.block];
+        let fd = task.file.as_ref().expect("files are opened before reading").as_raw_fd();
+        let target = &mut self.buffers[buffer][read.filled..len];
+        let entry = opcode::Read::new(types::Fd(fd), target.as_mut_ptr(), target.len() as u32)
+            .offset(offset + read.filled as u64)
+            .build()
+            .user_data(buffer as u64);
+
+        self.reads[buffer] = Some(read);
+        // SAFETY: the buffer outlives the read, since it is only reused once the read completes, and it is leaked if
+        // the reader is dropped while the read is in flight. There is a buffer per entry of the queue, so the queue
+        // can't be full.
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))
+    }
+}
+
+impl Drop for UringReader {
+    fn drop(&mut self) {
+        // the kernel might still be reading into the buffers of reads that were in flight when the ring failed, so
+        // they are leaked rather than freed
+        if self.reads.iter().any(Option::is_some) {
+            std::mem::forget(std::mem::take(&mut self.buffers));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hasher::testing::{file_hasher, hasher},
+        ChunkSchedule,
+    };
+
+    /// Create a reader, or return `None` if io_uring isn't available where the tests run.
+    ///
+    /// Setting `DUPED_REQUIRE_IO_URING` makes the tests fail instead, so that CI can make sure they run.
+    fn reader(queue_depth: u32) -> Option<UringReader> {
+        match UringReader::new(queue_depth) {
+            Ok(reader) => Some(reader),
+            Err(e) if std::env::var_os("DUPED_REQUIRE_IO_URING").is_some() => {
+                panic!("io_uring is required, but unavailable: {e}")
+            }
+            Err(e) => {
+                eprintln!("skipping the test, since io_uring is unavailable: {e}");
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn same_hashes_as_blocking_reads() {
+        let Some(mut reader) = reader(4) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = HasherConfig {
+            chunks: ChunkSchedule::fixed(3 * BLOCK_SIZE as u64),
+            ..Default::default()
+        };
+        let mut files: Vec<Vec<u8>> = [0, 1, BLOCK_SIZE + 1, 10 * BLOCK_SIZE - 3]
+            .iter()
+            .map(|&len| (0..len).map(|i| (i * 7 % 251) as u8).collect())
+            .collect();
+        // a sparse file, whose holes are not read
+        let mut sparse = vec![0; 20 * BLOCK_SIZE + 1];
+        sparse[8 * BLOCK_SIZE..9 * BLOCK_SIZE].fill(3);
+        files.push(sparse);
+
+        let mut hashers: Vec<_> = files
+            .iter()
+            .enumerate()
+            .map(|(i, data)| {
+                let path = dir.path().join(i.to_string());
+                let file = File::create(&path).unwrap();
+                file.set_len(data.len() as u64).unwrap();
+                let start = data.iter().position(|&b| b != 0).unwrap_or(data.len());
+                let end = data.iter().rposition(|&b| b != 0).map_or(start, |end| end + 1);
+                std::os::unix::fs::FileExt::write_all_at(&file, &data[start..end], start as u64)
+                    .unwrap();
+                file_hasher(&path)
+            })
+            .collect();
+        let mut hashes = vec![];
+        while !hashers.is_empty() {
+            let mut unfinished = vec![];
+            reader
+                .hash(hashers, &config, |hasher, res| {
+                    res.unwrap();
+                    match hasher.current_hash() {
+                        (hash, true) => hashes.push((hasher.file_path().path(), hash)),
+                        (_, false) => unfinished.push(hasher),
+                    }
+                    true
+                })
+                .unwrap();
+            hashers = unfinished;
+        }
+
+        assert_eq!(hashes.len(), files.len());
+        for (path, hash) in hashes {
+            let i: usize = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+            assert_eq!(hash, blake3::hash(&files[i]));
+        }
+    }
+
+    #[test]
+    fn failed_reads_give_the_buffers_of_later_blocks_back() {
+        let Some(mut reader) = reader(2) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = HasherConfig {
+            probes: crate::ProbeSchedule::disabled(),
+            chunks: ChunkSchedule::fixed(2 * BLOCK_SIZE as u64),
+            ..Default::default()
+        };
+        let mut task = Task::new(hasher(dir.path(), "a", &[1; 2 * BLOCK_SIZE]), &config);
+
+        // the second block is read first, so it waits for the first one, whose read fails
+        let buffers = [1, 0].map(|block| {
+            let buffer = reader.free.pop().unwrap();
+            reader.reads[buffer] = Some(Read { task: 0, block, filled: 0 });
+            task.in_flight += 1;
+            buffer
+        });
+        reader.complete(buffers[0], BLOCK_SIZE as i32, &mut task).unwrap();
+        assert_eq!(reader.free.len(), 0);
+        reader.complete(buffers[1], -libc::EIO, &mut task).unwrap();
+        assert!(task.is_done());
+        assert_eq!(reader.free.len(), 2);
+    }
+
+    #[test]
+    fn truncated_files_give_their_buffers_back() {
+        let queue_depth = 8;
+        let Some(mut reader) = reader(queue_depth) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let config = HasherConfig {
+            probes: crate::ProbeSchedule::disabled(),
+            chunks: ChunkSchedule::fixed(16 * BLOCK_SIZE as u64),
+            ..Default::default()
+        };
+        let hasher = |name: &str, len: usize| hasher(dir.path(), name, &vec![1; len]);
+
+        for _ in 0..4 {
+            // the files shrink between the walk and hashing, so the reads of their last blocks fail, while the reads
+            // of their first blocks might still be waiting to be fed
+            let hashers: Vec<_> = (0..4).map(|i| hasher(&i.to_string(), 16 * BLOCK_SIZE)).collect();
+            for i in 0..4 {
+                File::options()
+                    .write(true)
+                    .open(dir.path().join(i.to_string()))
+                    .unwrap()
+                    .set_len(5 * BLOCK_SIZE as u64 / 2)
+                    .unwrap();
+            }
+            let mut failed = 0;
+            reader
+                .hash(hashers, &config, |_, res| {
+                    assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+                    failed += 1;
+                    true
+                })
+                .unwrap();
+            assert_eq!(failed, 4);
+            assert_eq!(reader.free.len(), queue_depth as usize);
+        }
+
+        let data = vec![1; 3 * BLOCK_SIZE];
+        let mut hashers = vec![hasher("intact", data.len())];
+        while let Some(hasher) = hashers.pop() {
+            reader
+                .hash(vec![hasher], &config, |hasher, res| {
+                    res.unwrap();
+                    match hasher.current_hash() {
+                        (hash, true) => assert_eq!(hash, blake3::hash(&data)),
+                        (_, false) => hashers.push(hasher),
+                    }
+                    true
+                })
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,585 @@
+//! A parallel directory walker.
+//!
+//! Directories are read by a pool of threads that steal work from each other, while deciding which directories to
+//! descend into is left to the thread that drives the walk (see [`Walker::descend`]). This way, a
+//! [`crate::DeduperFileFilter`] is only ever called from a single thread, and in the order in which entries are
+//! discovered, just like with a sequential walk.
+
+use crate::file::DirNode;
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use tracing::warn;
+
+use std::{
+    collections::VecDeque,
+    fs::{self, Metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// Files that contain ignore patterns, in increasing order of precedence.
+pub(crate) const IGNORE_FILES: [&str; 3] = [".gitignore", ".ignore", ".dupedignore"];
+
+/// Options that control which entries are yielded by a [`Walker`].
+#[derive(Clone, Debug)]
+pub(crate) struct WalkOptions {
+    /// How many threads read directories.
+    pub(crate) threads: usize,
+    /// The maximum depth of an entry, relative to its root.
+    pub(crate) max_depth: Option<usize>,
+    /// Whether to skip entries whose name starts with a `.`.
+    pub(crate) skip_hidden: bool,
+    /// Whether to skip directories that are on a different file system than their root.
+    pub(crate) same_file_system: bool,
+    /// Whether to skip entries that are listed in ignore files.
+    pub(crate) respect_ignore_files: bool,
+    /// Whether the threads use the idle I/O scheduling class.
+    pub(crate) idle_io_priority: bool,
+}
+
+/// An entry found while walking.
+pub(crate) enum WalkEntry {
+    /// A directory, which is only walked if it is passed to [`Walker::descend`].
+    Dir(Dir),
+    /// Anything that is not a directory, along with its parent directory (`None` for roots).
+    File { root: usize, path: PathBuf, parent: Option<Arc<DirNode>>, metadata: Metadata },
+    /// An entry that couldn't be read.
+    Error { path: PathBuf, error: io::Error },
+    /// A directory some of whose entries were left out because of the options (depth, hidden, ignored, or on another
+    /// file system).
+    Pruned { dir: PathBuf },
+}
+
+/// A directory found while walking.
+pub(crate) struct Dir {
+    /// The index of the root this directory belongs to.
+    root: usize,
+    path: PathBuf,
+    /// The interned path of the directory, shared with the entries it contains.
+    node: Arc<DirNode>,
+    metadata: Metadata,
+    /// The depth of the directory, relative to its root.
+    depth: usize,
+    /// The device of the root.
+    root_device: Option<u64>,
+    /// The ignore patterns of all the parents of the directory.
+    ignores: Option<Arc<IgnoreChain>>,
+}
+
+impl Dir {
+    /// The index of the root this directory belongs to.
+    pub(crate) fn root(&self) -> usize {
+        self.root
+    }
+
+    /// The path of the directory.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The metadata of the directory.
+    pub(crate) fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
+
+/// The state shared by the [`Walker`] and its threads.
+struct Shared {
+    options: WalkOptions,
+    /// Directories that need to be read.
+    injector: Injector<Dir>,
+    /// Used to steal directories from other threads.
+    stealers: Vec<Stealer<Dir>>,
+    /// Incremented every time a directory is pushed, so that idle threads know when to wake up.
+    epoch: Mutex<u64>,
+    wake_up: Condvar,
+    /// Set when the walk is over.
+    stop: AtomicBool,
+}
+
+impl Shared {
+    /// Wake up all idle threads.
+    fn notify(&self) {
+        *self.epoch.lock().expect("poisoned lock") += 1;
+        self.wake_up.notify_all();
+    }
+}
+
+/// Walks a list of roots in parallel.
+///
+/// Each directory is read by one of the walker's threads, and its entries are yielded as a whole, so all the entries of
+/// a directory are yielded one after the other. The walk is over once all directories passed to [`Self::descend`] have
+/// been read, and all their entries yielded.
+pub(crate) struct Walker {
+    shared: Arc<Shared>,
+    /// The entries of every directory that was read.
+    entries: Receiver<Vec<WalkEntry>>,
+    /// Entries that were received, but not yielded yet.
+    buffer: VecDeque<WalkEntry>,
+    /// How many directories were passed to [`Self::descend`], but weren't read yet.
+    pending: usize,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Walker {
+    /// Create a new walker, which starts walking `roots` straight away.
+    pub(crate) fn new(roots: &[PathBuf], options: WalkOptions) -> Self {
+        let workers: Vec<_> = (0..options.threads.max(1)).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            options,
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            epoch: Mutex::new(0),
+            wake_up: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+
+        let (tx, entries) = mpsc::channel();
+        let threads = workers
+            .into_iter()
+            .map(|local| {
+                let shared = Arc::clone(&shared);
+                let tx = tx.clone();
+                std::thread::spawn(move || walker_task(&shared, &local, &tx))
+            })
+            .collect();
+
+        let mut walker = Self { shared, entries, buffer: VecDeque::new(), pending: 0, threads };
+        for (root, path) in roots.iter().enumerate() {
+            // roots are always followed, even if they are symbolic links
+            match fs::metadata(path) {
+                Ok(metadata) if metadata.is_dir() => walker.descend(Dir {
+                    root,
+                    path: path.clone(),
+                    node: DirNode::root(path),
+                    depth: 0,
+                    root_device: device(&metadata),
+                    metadata,
+                    ignores: None,
+                }),
+                Ok(metadata) => walker.buffer.push_back(WalkEntry::File {
+                    root,
+                    path: path.clone(),
+                    parent: None,
+                    metadata,
+                }),
+                Err(error) => {
+                    walker.buffer.push_back(WalkEntry::Error { path: path.clone(), error })
+                }
+            }
+        }
+
+        walker
+    }
+
+    /// Read the entries of `dir`, which are going to be yielded by the walker at some point.
+    pub(crate) fn descend(&mut self, dir: Dir) {
+        self.pending += 1;
+        self.shared.injector.push(dir);
+        self.shared.notify();
+    }
+
+    /// Returns whether [`Self::next`] can return without blocking.
+    pub(crate) fn is_ready(&mut self) -> bool {
+        while self.buffer.is_empty() && self.pending > 0 {
+            match self.entries.try_recv() {
+                Ok(entries) => {
+                    self.pending -= 1;
+                    self.buffer.extend(entries);
+                }
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+
+        true
+    }
+}
+
+impl Iterator for Walker {
+    type Item = WalkEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.buffer.pop_front() {
+                return Some(entry);
+            }
+            if self.pending == 0 {
+                return None;
+            }
+            let entries = self.entries.recv().ok()?;
+            self.pending -= 1;
+            self.buffer.extend(entries);
+        }
+    }
+}
+
+impl Drop for Walker {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.notify();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("walker thread panicked");
+            }
+        }
+    }
+}
+
+fn walker_task(shared: &Shared, local: &Worker<Dir>, tx: &Sender<Vec<WalkEntry>>) {
+    if shared.options.idle_io_priority {
+        if let Err(e) = crate::throttle::set_idle_io_priority() {
+            warn!(error = %e, "failed to set the I/O priority of a walker thread");
+        }
+    }
+
+    loop {
+        let epoch = *shared.epoch.lock().expect("poisoned lock");
+        if shared.stop.load(Ordering::Relaxed) {
+            return;
+        }
+
+        match find_dir(shared, local) {
+            Some(dir) => {
+                if tx.send(read_dir(&shared.options, dir)).is_err() {
+                    return;
+                }
+            }
+            None => {
+                let mut guard = shared.epoch.lock().expect("poisoned lock");
+                while *guard == epoch && !shared.stop.load(Ordering::Relaxed) {
+                    guard = shared.wake_up.wait(guard).expect("poisoned lock");
+                }
+            }
+        }
+    }
+}
+
+/// Find a directory to read, either in the local queue, in the global queue, or in the queue of another thread.
+fn find_dir(shared: &Shared, local: &Worker<Dir>) -> Option<Dir> {
+    local.pop().or_else(|| {
+        std::iter::repeat_with(|| {
+            shared
+                .injector
+                .steal_batch_and_pop(local)
+                .or_else(|| shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+    })
+}
+
+/// Read the entries of `dir`, skipping the ones that the options exclude.
+fn read_dir(options: &WalkOptions, dir: Dir) -> Vec<WalkEntry> {
+    let depth = dir.depth + 1;
+    if options.max_depth.is_some_and(|max| depth > max) {
+        // empty directories are the same at any depth
+        let is_empty = fs::read_dir(&dir.path).is_ok_and(|mut entries| entries.next().is_none());
+        return if is_empty { vec![] } else { vec![WalkEntry::Pruned { dir: dir.path }] };
+    }
+    let ignores =
+        if options.respect_ignore_files { IgnoreChain::load(&dir.path, dir.ignores) } else { None };
+
+    let read_dir = match fs::read_dir(&dir.path) {
+        Ok(read_dir) => read_dir,
+        Err(error) => return vec![WalkEntry::Error { path: dir.path, error }],
+    };
+    let mut entries = vec![];
+    let mut pruned = false;
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                entries.push(WalkEntry::Error { path: dir.path.clone(), error });
+                continue;
+            }
+        };
+        if options.skip_hidden && entry.file_name().as_encoded_bytes().starts_with(b".") {
+            pruned = true;
+            continue;
+        }
+
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                entries.push(WalkEntry::Error { path, error });
+                continue;
+            }
+        };
+        let is_dir = metadata.is_dir();
+        if ignores.as_ref().is_some_and(|ignores| ignores.is_ignored(&path, is_dir)) {
+            pruned = true;
+            continue;
+        }
+
+        if !is_dir {
+            let parent = Some(Arc::clone(&dir.node));
+            entries.push(WalkEntry::File { root: dir.root, path, parent, metadata });
+        } else if !options.same_file_system || device(&metadata) == dir.root_device {
+            entries.push(WalkEntry::Dir(Dir {
+                root: dir.root,
+                node: dir.node.child(&entry.file_name()),
+                path,
+                metadata,
+                depth,
+                root_device: dir.root_device,
+                ignores: ignores.clone(),
+            }));
+        } else {
+            pruned = true;
+        }
+    }
+    if pruned {
+        entries.push(WalkEntry::Pruned { dir: dir.path });
+    }
+
+    entries
+}
+
+/// Return the device the entry is on.
+fn device(metadata: &Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        Some(metadata.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// The ignore patterns of a directory, and of all its parents.
+///
+/// Patterns of deeper directories take precedence over the patterns of their parents, which means a
+/// `.dupedignore` file can re-include (`!pattern`) something that a parent directory ignored.
+pub(crate) struct IgnoreChain {
+    gitignore: Gitignore,
+    parent: Option<Arc<IgnoreChain>>,
+}
+
+impl IgnoreChain {
+    /// Load the ignore files of `dir`, and return a chain with `parent` as its parent.
+    ///
+    /// If `dir` has no ignore files, `parent` is returned as is.
+    fn load(dir: &Path, parent: Option<Arc<IgnoreChain>>) -> Option<Arc<IgnoreChain>> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in IGNORE_FILES {
+            let path = dir.join(name);
+            if !path.is_file() {
+                continue;
+            }
+            found = true;
+            if let Some(e) = builder.add(&path) {
+                warn!(error = %e, path = %path.display(), "failed to parse ignore file");
+            }
+        }
+        if !found {
+            return parent;
+        }
+
+        match builder.build() {
+            Ok(gitignore) => Some(Arc::new(IgnoreChain { gitignore, parent })),
+            Err(e) => {
+                warn!(error = %e, path = %dir.display(), "invalid ignore patterns");
+                parent
+            }
+        }
+    }
+
+    /// Return whether `path` is ignored by this chain.
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut chain = Some(self);
+        while let Some(ignores) = chain {
+            match ignores.gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => chain = ignores.parent.as_deref(),
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> WalkOptions {
+        WalkOptions {
+            threads: 4,
+            max_depth: None,
+            skip_hidden: false,
+            same_file_system: false,
+            respect_ignore_files: false,
+            idle_io_priority: false,
+        }
+    }
+
+    /// Create the files at `paths` (relative to `root`), and their parent directories.
+    fn build(root: &Path, paths: &[&str]) {
+        for path in paths {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+    }
+
+    /// Walk `roots` entirely, and return the files (with the index of their root, and relative to it) and the pruned
+    /// directories (relative to the first root) that were found.
+    fn walk(roots: &[PathBuf], options: WalkOptions) -> (Vec<(usize, PathBuf)>, Vec<PathBuf>) {
+        let mut walker = Walker::new(roots, options);
+        let (mut files, mut pruned) = (vec![], vec![]);
+        while let Some(entry) = walker.next() {
+            match entry {
+                WalkEntry::Dir(dir) => walker.descend(dir),
+                WalkEntry::File { root, path, .. } => {
+                    files.push((root, path.strip_prefix(&roots[root]).unwrap().to_owned()))
+                }
+                WalkEntry::Error { path, error } => panic!("{}: {error}", path.display()),
+                WalkEntry::Pruned { dir } => {
+                    pruned.push(dir.strip_prefix(&roots[0]).unwrap().to_owned())
+                }
+            }
+        }
+        files.sort();
+        pruned.sort();
+
+        (files, pruned)
+    }
+
+    #[test]
+    fn roots_are_walked_concurrently() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let paths: Vec<_> = (0..50).map(|i| format!("{}/{}/file", i % 7, i)).collect();
+        let paths: Vec<_> = paths.iter().map(String::as_str).collect();
+        for dir in &dirs {
+            build(dir.path(), &paths);
+        }
+        let roots: Vec<_> = dirs.iter().map(|dir| dir.path().to_owned()).collect();
+
+        let (files, pruned) = walk(&roots, options());
+        let mut expected: Vec<_> = (0..2)
+            .flat_map(|root| paths.iter().map(move |path| (root, PathBuf::from(path))))
+            .collect();
+        expected.sort();
+        assert_eq!(files, expected);
+        assert!(pruned.is_empty());
+    }
+
+    #[test]
+    fn directories_that_are_not_descended_are_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), &["a", "sub/b"]);
+
+        let mut walker = Walker::new(&[dir.path().to_owned()], options());
+        let names: Vec<_> = walker
+            .by_ref()
+            .map(|entry| match entry {
+                WalkEntry::Dir(dir) => dir.path().to_owned(),
+                WalkEntry::File { path, .. } => path,
+                _ => panic!("unexpected entry"),
+            })
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&dir.path().join("a")));
+        assert!(names.contains(&dir.path().join("sub")));
+    }
+
+    #[test]
+    fn max_depth_prunes_deeper_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), &["a", "sub/b", "sub/deeper/c"]);
+        fs::create_dir(dir.path().join("sub/empty")).unwrap();
+
+        let options = WalkOptions { max_depth: Some(2), ..options() };
+        let (files, pruned) = walk(&[dir.path().to_owned()], options);
+        assert_eq!(files, [(0, PathBuf::from("a")), (0, PathBuf::from("sub/b"))]);
+        // empty directories have nothing to leave out
+        assert_eq!(pruned, [PathBuf::from("sub/deeper")]);
+    }
+
+    #[test]
+    fn hidden_entries_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), &[".hidden", ".hidden_dir/a", "sub/b", "sub/.c"]);
+
+        let (files, pruned) = walk(&[dir.path().to_owned()], options());
+        assert_eq!(files.len(), 4);
+        assert!(pruned.is_empty());
+
+        let options = WalkOptions { skip_hidden: true, ..options() };
+        let (files, pruned) = walk(&[dir.path().to_owned()], options);
+        assert_eq!(files, [(0, PathBuf::from("sub/b"))]);
+        assert_eq!(pruned, [PathBuf::new(), PathBuf::from("sub")]);
+    }
+
+    #[test]
+    fn ignore_files_are_chained() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), &["a.log", "b", "ignored/c", "keep/d.log", "keep/e"]);
+        fs::write(dir.path().join(".gitignore"), "*.log\nignored/\n").unwrap();
+        // deeper ignore files take precedence, and `.dupedignore` over `.gitignore`
+        fs::write(dir.path().join("keep/.gitignore"), "e\n").unwrap();
+        fs::write(dir.path().join("keep/.dupedignore"), "!*.log\n").unwrap();
+
+        let options = WalkOptions { respect_ignore_files: true, ..options() };
+        let (files, pruned) = walk(&[dir.path().to_owned()], options);
+        let files: Vec<_> = files.into_iter().map(|(_, path)| path).collect();
+        assert_eq!(
+            files,
+            [".gitignore", "b", "keep/.dupedignore", "keep/.gitignore", "keep/d.log"]
+                .map(PathBuf::from)
+        );
+        assert_eq!(pruned, [PathBuf::new(), PathBuf::from("keep")]);
+    }
+
+    #[test]
+    fn other_file_systems_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), &["a", "sub/b"]);
+        let metadata = fs::metadata(dir.path()).unwrap();
+        let read = |same_file_system| {
+            let root = Dir {
+                root: 0,
+                path: dir.path().to_owned(),
+                node: DirNode::root(dir.path()),
+                depth: 0,
+                // pretend that the root is on another device than its entries
+                root_device: device(&metadata).map(|device| device + 1),
+                metadata: metadata.clone(),
+                ignores: None,
+            };
+            let options = WalkOptions { same_file_system, ..options() };
+            let mut kinds: Vec<_> = read_dir(&options, root)
+                .into_iter()
+                .map(|entry| match entry {
+                    WalkEntry::Dir(_) => "dir",
+                    WalkEntry::File { .. } => "file",
+                    WalkEntry::Error { .. } => "error",
+                    WalkEntry::Pruned { .. } => "pruned",
+                })
+                .collect();
+            kinds.sort();
+            kinds
+        };
+
+        assert_eq!(read(false), ["dir", "file"]);
+        assert_eq!(read(true), ["file", "pruned"]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Measures how much memory a search takes per file, by counting the bytes allocated on the heap.
+//!
+//! The numbers are printed, so run with `cargo test -p duped --test memory -- --nocapture` to see them. The budget is
+//! loose, it only catches regressions that blow memory usage up, e.g. keeping blake3 hashers or whole paths around.
+
+use duped::{ContentLimit, Deduper, DeduperBuilder, NoopFindHook, ProbeSchedule};
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts the bytes allocated on the heap, and the peak.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const DIRS: usize = 20;
+const FILES_PER_DIR: usize = 250;
+/// The maximum number of bytes allocated per file during a search.
+const BUDGET_PER_FILE: usize = 1024;
+
+/// Write files that all have the same size and the same head, in nested directories with long names, so that they are
+/// all candidates until their tail is probed.
+fn build_corpus(root: &Path) {
+    for d in 0..DIRS {
+        let dir = root.join(format!("a-rather-long-directory-name-{d}/with-a-subdirectory"));
+        std::fs::create_dir_all(&dir).unwrap();
+        for f in 0..FILES_PER_DIR {
+            let mut data = [0; 128];
+            data[120..].copy_from_slice(&((d * FILES_PER_DIR + f) as u64).to_le_bytes());
+            std::fs::write(dir.join(format!("file-{f}")), data).unwrap();
+        }
+    }
+}
+
+/// Probe the first and last 16 bytes of each file.
+fn builder(root: &Path) -> DeduperBuilder {
+    let probes = ProbeSchedule::new().with_head(16).with_tail(16).with_samples(0, 0);
+    Deduper::builder(vec![root.to_owned()]).probe_schedule(probes)
+}
+
+/// Run a search, and return the peak number of bytes allocated per file while it ran.
+fn peak_per_file(deduper: Deduper) -> usize {
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let result = deduper.find(ContentLimit::no_limit(), NoopFindHook).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    assert_eq!(result.hashes().len(), DIRS * FILES_PER_DIR);
+    drop(result);
+
+    peak / (DIRS * FILES_PER_DIR)
+}
+
+#[test]
+fn memory_per_file() {
+    let dir = tempfile::tempdir().unwrap();
+    build_corpus(dir.path());
+    let spill = tempfile::tempdir().unwrap();
+
+    let in_memory = peak_per_file(builder(dir.path()).build());
+    let spilled =
+        peak_per_file(builder(dir.path()).spill_candidates(spill.path().to_owned(), 100).build());
+    println!("peak bytes per file: {in_memory} in memory, {spilled} with spilling");
+
+    assert!(in_memory <= BUDGET_PER_FILE, "{in_memory} bytes per file");
+    assert!(spilled < in_memory, "{spilled} bytes per file");
+}